@@ -0,0 +1,362 @@
+use cairo_rs::Context;
+use pango::{Rectangle, SCALE};
+use serde::Serialize;
+use serde_json::json;
+
+use crate::cairopango::Run;
+use crate::input::Text;
+use crate::raster::project;
+
+/// Corners of a box in output coordinates: top left, top right, bottom right, bottom left
+pub type Quad = [(f64, f64); 4];
+
+#[derive(Serialize, Clone, Debug)]
+pub struct TextBox {
+    pub text: String,
+    pub quad: Quad,
+}
+
+/// Boxes of the drawn text of one item, transforms are applied
+#[derive(Serialize, Clone, Debug)]
+pub struct Annotation {
+    /// index of the item in Data::items
+    pub item: usize,
+    pub text: String,
+    /// the textblock followed by its frames
+    pub polygons: Vec<Quad>,
+    pub lines: Vec<TextBox>,
+    pub words: Vec<TextBox>,
+    pub glyphs: Vec<TextBox>,
+}
+
+/// Box in layout coordinates
+#[derive(Clone)]
+struct Extent {
+    text: String,
+    x: f64,
+    y: f64,
+    width: f64,
+    height: f64,
+}
+
+impl Extent {
+    fn new(text: &str, rect: &Rectangle, dx: f64, dy: f64) -> Extent {
+        let scale = SCALE as f64;
+        Extent {
+            text: text.to_string(),
+            x: dx + rect.x() as f64 / scale,
+            y: dy + rect.y() as f64 / scale,
+            width: rect.width() as f64 / scale,
+            height: rect.height() as f64 / scale,
+        }
+    }
+
+    fn join(&mut self, other: &Extent) {
+        let right = (self.x + self.width).max(other.x + other.width);
+        let bottom = (self.y + self.height).max(other.y + other.height);
+        self.x = self.x.min(other.x);
+        self.y = self.y.min(other.y);
+        self.width = right - self.x;
+        self.height = bottom - self.y;
+        self.text.push_str(&other.text);
+    }
+
+    fn to_box(&self, map: &impl Fn((f64, f64)) -> (f64, f64)) -> TextBox {
+        TextBox {
+            text: self.text.clone(),
+            quad: [
+                map((self.x, self.y)),
+                map((self.x + self.width, self.y)),
+                map((self.x + self.width, self.y + self.height)),
+                map((self.x, self.y + self.height)),
+            ],
+        }
+    }
+}
+
+impl Text {
+    /// Collects the boxes of the runs as they are drawn with the current transform of the context
+    pub(crate) fn annotate(
+        &self,
+        item: usize,
+        runs: &[Run],
+        context: &Context,
+        vertical: bool,
+    ) -> Result<Annotation, cairo_rs::Error> {
+        let mut annotation = Annotation {
+            item,
+            text: self.value.clone(),
+            polygons: vec![],
+            lines: vec![],
+            words: vec![],
+            glyphs: vec![],
+        };
+        let projection = self.projection();
+        let device = |x: f64, y: f64| {
+            let point = context.user_to_device(x, y);
+            match &projection {
+                Some(projection) => project(projection, point),
+                None => point,
+            }
+        };
+        let boxes = std::iter::once((&self.pos, &self.size))
+            .chain(self.frames.iter().map(|frame| (&frame.pos, &frame.size)));
+        for (pos, size) in boxes {
+            let rect = self.frame_rect(pos, size, vertical);
+            annotation.polygons.push(
+                [
+                    (rect.x, rect.y),
+                    (rect.x + rect.width, rect.y),
+                    (rect.x + rect.width, rect.y + rect.height),
+                    (rect.x, rect.y + rect.height),
+                ]
+                .map(|(x, y)| device(x, y)),
+            );
+        }
+
+        for run in runs {
+            let (lines, clusters) = extents(run);
+            let mut words: Vec<Extent> = vec![];
+            let mut in_word = false;
+            for cluster in &clusters {
+                if cluster.text.trim().is_empty() {
+                    in_word = false;
+                    continue;
+                }
+                match words.last_mut() {
+                    Some(word) if in_word => word.join(cluster),
+                    _ => words.push(cluster.clone()),
+                }
+                in_word = true;
+            }
+
+            let map: Box<dyn Fn((f64, f64)) -> (f64, f64) + '_> = match &self.path {
+                None => Box::new(|(x, y)| device(x, y)),
+                Some(path) => {
+                    let along = path.mapping(&run.layout, context, &self.pos)?;
+                    let origin = (run.x, run.y);
+                    Box::new(move |(x, y)| {
+                        let (x, y) = along((x - origin.0, y - origin.1));
+                        device(x, y)
+                    })
+                }
+            };
+            annotation
+                .lines
+                .extend(lines.iter().map(|line| line.to_box(&map)));
+            annotation
+                .words
+                .extend(words.iter().map(|word| word.to_box(&map)));
+            annotation.glyphs.extend(
+                clusters
+                    .iter()
+                    .filter(|cluster| !cluster.text.trim().is_empty())
+                    .map(|cluster| cluster.to_box(&map)),
+            );
+        }
+        Ok(annotation)
+    }
+}
+
+/// Logical boxes of the lines and clusters of the run in user coordinates
+/// Text on a path is returned relative to the layout origin
+fn extents(run: &Run) -> (Vec<Extent>, Vec<Extent>) {
+    let layout = &run.layout;
+    let text = layout.text();
+    let selected = run.line.as_ref().map(|line| line.start_index());
+    let mut lines = vec![];
+    let mut clusters = vec![];
+
+    let mut iter = layout.iter();
+    let mut offset = (run.x, run.y);
+    loop {
+        let line = match iter.line_readonly() {
+            Some(line) => line,
+            None => break,
+        };
+        let (_, logical) = iter.line_extents();
+        if selected.is_none() || selected == Some(line.start_index()) {
+            if selected.is_some() {
+                // a single line is drawn with its baseline at the position of the run
+                let scale = SCALE as f64;
+                offset = (
+                    run.x - logical.x() as f64 / scale,
+                    run.y - iter.baseline() as f64 / scale,
+                );
+            }
+            let start = line.start_index() as usize;
+            let end = start + line.length() as usize;
+            lines.push(Extent::new(
+                text[start..end].trim_end(),
+                &logical,
+                offset.0,
+                offset.1,
+            ));
+
+            let mut clusters_iter = layout.iter();
+            loop {
+                let index = clusters_iter.index() as usize;
+                let (_, cluster) = clusters_iter.cluster_extents();
+                let more = clusters_iter.next_cluster();
+                if index >= start && index < end {
+                    let next = match more {
+                        true => (clusters_iter.index() as usize).min(end),
+                        false => end,
+                    };
+                    clusters.push(Extent::new(
+                        &text[index..next.max(index)],
+                        &cluster,
+                        offset.0,
+                        offset.1,
+                    ));
+                }
+                if !more || clusters_iter.index() as usize >= end {
+                    break;
+                }
+            }
+        }
+        if !iter.next_line() {
+            break;
+        }
+    }
+    (lines, clusters)
+}
+
+/// Every annotation as json
+pub fn to_json(annotations: &[Annotation]) -> Result<String, serde_json::Error> {
+    serde_json::to_string(annotations)
+}
+
+/// ICDAR 2015 ground truth, one word per line: x1,y1,x2,y2,x3,y3,x4,y4,transcription
+pub fn to_icdar(annotations: &[Annotation]) -> String {
+    let mut out = String::new();
+    for word in annotations.iter().flat_map(|annotation| &annotation.words) {
+        for (x, y) in word.quad {
+            out.push_str(&format!("{},{},", x.round() as i64, y.round() as i64));
+        }
+        out.push_str(&word.text);
+        out.push('\n');
+    }
+    out
+}
+
+/// COCO-Text annotations of the words of one image
+pub fn to_coco_text(
+    annotations: &[Annotation],
+    image_id: u64,
+    file_name: &str,
+    width: f64,
+    height: f64,
+) -> Result<String, serde_json::Error> {
+    let mut anns = serde_json::Map::new();
+    let mut ids = vec![];
+    for (id, word) in annotations
+        .iter()
+        .flat_map(|annotation| &annotation.words)
+        .enumerate()
+    {
+        let left = word.quad.iter().map(|p| p.0).fold(f64::INFINITY, f64::min);
+        let top = word.quad.iter().map(|p| p.1).fold(f64::INFINITY, f64::min);
+        let right = word
+            .quad
+            .iter()
+            .map(|p| p.0)
+            .fold(f64::NEG_INFINITY, f64::max);
+        let bottom = word
+            .quad
+            .iter()
+            .map(|p| p.1)
+            .fold(f64::NEG_INFINITY, f64::max);
+        let mask = word
+            .quad
+            .iter()
+            .flat_map(|&(x, y)| [x, y])
+            .collect::<Vec<_>>();
+        ids.push(id);
+        anns.insert(
+            id.to_string(),
+            json!({
+                "id": id,
+                "image_id": image_id,
+                "bbox": [left, top, right - left, bottom - top],
+                "mask": mask,
+                "area": (right - left) * (bottom - top),
+                "utf8_string": word.text,
+                "class": "machine printed",
+                "legibility": "legible",
+                "language": "na",
+            }),
+        );
+    }
+    serde_json::to_string(&json!({
+        "imgs": {
+            image_id.to_string(): {
+                "id": image_id,
+                "file_name": file_name,
+                "width": width,
+                "height": height,
+                "set": "train",
+            }
+        },
+        "anns": anns,
+        "imgToAnns": { image_id.to_string(): ids },
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::{to_coco_text, to_icdar, to_json, Annotation, TextBox};
+
+    fn annotation() -> Annotation {
+        let word = |text: &str, x: f64| TextBox {
+            text: text.to_string(),
+            quad: [(x, 10.), (x + 20., 10.), (x + 20., 20.4), (x, 20.4)],
+        };
+        Annotation {
+            item: 0,
+            text: "ab cd".to_string(),
+            polygons: vec![[(0., 0.), (100., 0.), (100., 50.), (0., 50.)]],
+            lines: vec![],
+            words: vec![word("ab", 5.), word("cd", 30.)],
+            glyphs: vec![],
+        }
+    }
+
+    #[test]
+    fn icdar_has_a_rounded_line_per_word() {
+        assert_eq!(
+            to_icdar(&[annotation()]),
+            "5,10,25,10,25,20,5,20,ab\n30,10,50,10,50,20,30,20,cd\n"
+        );
+    }
+
+    #[test]
+    fn coco_text_boxes_the_words() {
+        let coco: Value =
+            serde_json::from_str(&to_coco_text(&[annotation()], 7, "a.png", 100., 50.).unwrap())
+                .unwrap();
+        assert_eq!(coco["imgs"]["7"]["file_name"], "a.png");
+        assert_eq!(coco["imgToAnns"]["7"], serde_json::json!([0, 1]));
+        let ann = &coco["anns"]["1"];
+        assert_eq!(ann["image_id"], 7);
+        assert_eq!(ann["utf8_string"], "cd");
+        let bbox = ann["bbox"].as_array().unwrap();
+        let bbox = bbox.iter().map(|v| v.as_f64().unwrap()).collect::<Vec<_>>();
+        assert_eq!(bbox[..3], [30., 10., 20.]);
+        assert!((bbox[3] - 10.4).abs() < 1e-9);
+        assert_eq!(ann["mask"].as_array().unwrap().len(), 8);
+    }
+
+    #[test]
+    fn json_keeps_every_field() {
+        let json: Value = serde_json::from_str(&to_json(&[annotation()]).unwrap()).unwrap();
+        assert_eq!(json[0]["text"], "ab cd");
+        assert_eq!(
+            json[0]["words"][1]["quad"][1],
+            serde_json::json!([50., 10.])
+        );
+        assert_eq!(json[0]["polygons"].as_array().unwrap().len(), 1);
+    }
+}
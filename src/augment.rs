@@ -0,0 +1,284 @@
+use std::ops::Range;
+
+use image::codecs::jpeg::JpegEncoder;
+use image::RgbaImage;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+use crate::effects::gaussian_blur;
+use crate::raster::{bilinear, homography, premultiply, project, unpremultiply};
+
+/// Augmentations applied in order to raster output
+pub struct Augmentations {
+    pub steps: Vec<Augmentation>,
+    /// Same seed and steps give the same result
+    pub seed: u64,
+}
+
+/// Augmentation that is applied with a probability
+/// Parameters are picked from the ranges, an empty range like 2.0..2.0 always gives its start
+pub struct Augmentation {
+    /// chance from 0 to 1
+    pub probability: f64,
+    pub kind: AugmentationKind,
+}
+
+pub enum AugmentationKind {
+    /// standard deviation in px
+    GaussianBlur { sigma: Range<f64> },
+    /// length of the streak in px and its direction in degrees
+    MotionBlur {
+        length: Range<f64>,
+        angle: Range<f64>,
+    },
+    /// standard deviation of the gaussian noise, 0-255
+    Noise { sigma: Range<f64> },
+    /// quality of the jpeg compression, up to 100
+    Jpeg { quality: Range<u8> },
+    /// brightness is added, -1 to 1, contrast is a factor around the middle gray
+    BrightnessContrast {
+        brightness: Range<f64>,
+        contrast: Range<f64>,
+    },
+    /// every corner moves inwards by up to strength times the size of the image
+    Perspective { strength: Range<f64> },
+    /// darkens the image with blotches of size scale in px, strength from 0 to 1
+    PaperTexture {
+        strength: Range<f64>,
+        scale: Range<f64>,
+    },
+}
+
+impl Augmentations {
+    pub(crate) fn apply(&self, image: &mut RgbaImage) {
+        let mut rng = ChaCha8Rng::seed_from_u64(self.seed);
+        for step in &self.steps {
+            if rng.gen_bool(step.probability.clamp(0., 1.)) {
+                step.kind.apply(image, &mut rng);
+            }
+        }
+    }
+}
+
+impl AugmentationKind {
+    fn apply(&self, image: &mut RgbaImage, rng: &mut ChaCha8Rng) {
+        let (width, height) = image.dimensions();
+        match self {
+            AugmentationKind::GaussianBlur { sigma } => {
+                let sigma = sample(rng, sigma);
+                // with straight alpha the color of transparent pixels would bleed into the edges
+                premultiply(image);
+                gaussian_blur(
+                    image,
+                    width as usize,
+                    height as usize,
+                    width as usize * 4,
+                    4,
+                    sigma,
+                );
+                unpremultiply(image);
+            }
+            AugmentationKind::MotionBlur { length, angle } => {
+                let length = sample(rng, length);
+                let angle = sample(rng, angle).to_radians();
+                let steps = length.ceil().max(1.) as i64;
+                premultiply(image);
+                let source = image.clone();
+                for (x, y, pixel) in image.enumerate_pixels_mut() {
+                    let mut sum = [0.; 4];
+                    for step in 0..=steps {
+                        let t = (step as f64 / steps as f64 - 0.5) * length;
+                        let sx = (x as f64 + t * angle.cos()).round();
+                        let sy = (y as f64 + t * angle.sin()).round();
+                        let sx = sx.clamp(0., width as f64 - 1.) as u32;
+                        let sy = sy.clamp(0., height as f64 - 1.) as u32;
+                        for (s, c) in sum.iter_mut().zip(source.get_pixel(sx, sy).0) {
+                            *s += c as f64;
+                        }
+                    }
+                    for (c, s) in pixel.0.iter_mut().zip(sum) {
+                        *c = (s / (steps + 1) as f64).round() as u8;
+                    }
+                }
+                unpremultiply(image);
+            }
+            AugmentationKind::Noise { sigma } => {
+                let sigma = sample(rng, sigma);
+                for pixel in image.pixels_mut() {
+                    for c in &mut pixel.0[..3] {
+                        *c = (*c as f64 + normal(rng) * sigma).round().clamp(0., 255.) as u8;
+                    }
+                }
+            }
+            AugmentationKind::Jpeg { quality } => {
+                let quality = match quality.is_empty() {
+                    true => quality.start,
+                    false => rng.gen_range(quality.clone()),
+                };
+                let rgb = image::DynamicImage::ImageRgba8(image.clone()).into_rgb8();
+                let mut bytes = vec![];
+                let encoded = JpegEncoder::new_with_quality(&mut bytes, quality.clamp(1, 100))
+                    .encode_image(&rgb);
+                let decoded = encoded.ok().and_then(|_| {
+                    image::load_from_memory_with_format(&bytes, image::ImageFormat::Jpeg).ok()
+                });
+                if let Some(decoded) = decoded {
+                    for (pixel, jpeg) in image.pixels_mut().zip(decoded.into_rgb8().pixels()) {
+                        pixel.0[..3].copy_from_slice(&jpeg.0);
+                    }
+                }
+            }
+            AugmentationKind::BrightnessContrast {
+                brightness,
+                contrast,
+            } => {
+                let brightness = sample(rng, brightness) * 255.;
+                let contrast = sample(rng, contrast);
+                for pixel in image.pixels_mut() {
+                    for c in &mut pixel.0[..3] {
+                        let value = (*c as f64 - 127.5) * contrast + 127.5 + brightness;
+                        *c = value.round().clamp(0., 255.) as u8;
+                    }
+                }
+            }
+            AugmentationKind::Perspective { strength } => {
+                let strength = sample(rng, strength);
+                let (w, h) = (width as f64, height as f64);
+                let corners = [(0., 0.), (w, 0.), (w, h), (0., h)];
+                let mut moved = corners;
+                for (i, corner) in moved.iter_mut().enumerate() {
+                    let dx = rng.gen::<f64>() * strength * w;
+                    let dy = rng.gen::<f64>() * strength * h;
+                    corner.0 += if i == 1 || i == 2 { -dx } else { dx };
+                    corner.1 += if i >= 2 { -dy } else { dy };
+                }
+                if let Some(matrix) = homography(&moved, &corners) {
+                    *image = warp(image, &matrix, width, height);
+                }
+            }
+            AugmentationKind::PaperTexture { strength, scale } => {
+                let strength = sample(rng, strength).clamp(0., 1.);
+                let scale = sample(rng, scale).max(1.);
+                let columns = (width as f64 / scale).ceil() as usize + 2;
+                let rows = (height as f64 / scale).ceil() as usize + 2;
+                let grid = (0..columns * rows)
+                    .map(|_| rng.gen::<f64>())
+                    .collect::<Vec<_>>();
+                for (x, y, pixel) in image.enumerate_pixels_mut() {
+                    let (gx, gy) = (x as f64 / scale, y as f64 / scale);
+                    let (cx, cy) = (gx as usize, gy as usize);
+                    let (fx, fy) = (gx.fract(), gy.fract());
+                    let at = |c: usize, r: usize| grid[r * columns + c];
+                    let top = at(cx, cy) * (1. - fx) + at(cx + 1, cy) * fx;
+                    let bottom = at(cx, cy + 1) * (1. - fx) + at(cx + 1, cy + 1) * fx;
+                    let blotch = top * (1. - fy) + bottom * fy;
+                    let grain = rng.gen::<f64>();
+                    let factor = 1. - strength * (0.7 * blotch + 0.3 * grain);
+                    for c in &mut pixel.0[..3] {
+                        *c = (*c as f64 * factor).round() as u8;
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn sample(rng: &mut ChaCha8Rng, range: &Range<f64>) -> f64 {
+    match range.is_empty() {
+        true => range.start,
+        false => rng.gen_range(range.clone()),
+    }
+}
+
+/// Standard normal distributed number
+fn normal(rng: &mut ChaCha8Rng) -> f64 {
+    let u = 1. - rng.gen::<f64>();
+    let v = rng.gen::<f64>();
+    (-2. * u.ln()).sqrt() * (2. * std::f64::consts::PI * v).cos()
+}
+
+/// Resamples the image, inverse maps a pixel of the result to the source
+/// Pixels outside of the source are transparent
+fn warp(source: &RgbaImage, inverse: &[f64; 9], width: u32, height: u32) -> RgbaImage {
+    RgbaImage::from_fn(width, height, |x, y| {
+        let (sx, sy) = project(inverse, (x as f64 + 0.5, y as f64 + 0.5));
+        bilinear(source, sx - 0.5, sy - 0.5)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgba, RgbaImage};
+
+    use super::{Augmentation, AugmentationKind, Augmentations};
+
+    fn every_step(seed: u64) -> Augmentations {
+        let kinds = vec![
+            AugmentationKind::GaussianBlur { sigma: 0.5..2. },
+            AugmentationKind::MotionBlur {
+                length: 1.0..4.,
+                angle: 0.0..180.,
+            },
+            AugmentationKind::Noise { sigma: 1.0..10. },
+            AugmentationKind::Jpeg { quality: 20..90 },
+            AugmentationKind::BrightnessContrast {
+                brightness: -0.2..0.2,
+                contrast: 0.8..1.2,
+            },
+            AugmentationKind::Perspective { strength: 0.0..0.1 },
+            AugmentationKind::PaperTexture {
+                strength: 0.1..0.5,
+                scale: 2.0..8.,
+            },
+        ];
+        Augmentations {
+            steps: kinds
+                .into_iter()
+                .map(|kind| Augmentation {
+                    probability: 1.,
+                    kind,
+                })
+                .collect(),
+            seed,
+        }
+    }
+
+    fn image() -> RgbaImage {
+        RgbaImage::from_fn(32, 24, |x, y| {
+            Rgba([(x * 8) as u8, (y * 10) as u8, ((x + y) * 4) as u8, 255])
+        })
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_image() {
+        let (mut a, mut b) = (image(), image());
+        every_step(7).apply(&mut a);
+        every_step(7).apply(&mut b);
+        assert_eq!(a, b);
+        let mut c = image();
+        every_step(8).apply(&mut c);
+        assert_ne!(a, c);
+    }
+
+    #[test]
+    fn blur_keeps_transparent_colors_out() {
+        // transparent red around an opaque blue square
+        let mut image = RgbaImage::from_fn(16, 16, |x, y| {
+            match (4..12).contains(&x) && (4..12).contains(&y) {
+                true => Rgba([0, 0, 255, 255]),
+                false => Rgba([255, 0, 0, 0]),
+            }
+        });
+        let blur = Augmentations {
+            steps: vec![Augmentation {
+                probability: 1.,
+                kind: AugmentationKind::GaussianBlur { sigma: 2.0..2.5 },
+            }],
+            seed: 0,
+        };
+        blur.apply(&mut image);
+        let edge = image.get_pixel(3, 8).0;
+        assert!(edge[3] > 0 && edge[3] < 255);
+        assert!(edge[0] < 8, "{:?}", edge);
+    }
+}
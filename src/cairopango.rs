@@ -1,16 +1,31 @@
 use std::f32::consts::PI;
 use std::io::{Cursor, Write};
 
-use cairo_rs::{Context, Format, ImageSurface, LineJoin, PdfSurface, PsSurface, SvgSurface};
+use cairo_rs::{
+    Context, Extend, Filter, Format, ImageSurface, LineCap, LineJoin, Matrix, Operator, PdfSurface,
+    PsSurface, SurfacePattern, SvgSurface,
+};
+use image::ImageError;
 use pango::{
-    Alignment, Direction, FontDescription, Layout, Stretch, Style, Variant, Weight, SCALE,
+    Alignment, Direction, FontDescription, Layout, LayoutLine, Stretch, Style, Variant, Weight,
+    SCALE,
 };
-use pangocairo::{create_context, layout_path};
+use pangocairo::{create_context, layout_line_path, layout_path};
+use resvg::usvg;
+
+use crate::annotation::Annotation;
+use crate::augment::Augmentations;
+use crate::effects::{pixels, VECTOR_RASTER_SCALE};
+use crate::flow::{flow, remaining, thread, Rect};
+use crate::ora::Capture;
+use crate::raster::{from_image, to_image};
 
 use crate::input::{
-    Alignments, Background, Data, Ellipsize, FontStretch, FontStyle, FontVariant, FontWeight,
-    HorizontalAlignment, Mode, OutputMode, Pos2, ReadDirection, Rgb, Rgba, Size2, Styling, Text,
-    VerticalAlignment, Wrap,
+    Alignments, Anchor, Background, BackgroundImage, BlendMode, BoxShape, BoxStyle, ColorStop,
+    Data, Ellipsize, FontStretch, FontStyle, FontVariant, FontWeight, HorizontalAlignment,
+    ImageFilter, ImageFit, Item, Layer, Mode, OutputMode, OverflowPolicy, Padding, Paint,
+    PaintOrder, Pos2, ReadDirection, Region, Rgb, Rgba, Size2, Stroke, StrokeCap, StrokeJoin,
+    Styling, Text, Transform, VerticalAlignment, Wrap,
 };
 
 enum Surfaces {
@@ -20,14 +35,81 @@ enum Surfaces {
     Ps(PsSurface),
 }
 
+/// Layout placed with its upper left corner at x and y in user space
+/// or a single line of it with the start of its baseline at x and y
+pub(crate) struct Run {
+    pub(crate) layout: Layout,
+    pub(crate) line: Option<LayoutLine>,
+    pub(crate) x: f64,
+    pub(crate) y: f64,
+}
+
+/// Text that didn't fit into its box
+#[derive(Debug)]
+pub struct Overflow {
+    /// index of the item in Data::items
+    pub item: usize,
+    /// Text of the lines outside of the box, empty if the text is only too wide or shortened
+    pub remaining: String,
+    /// fontsize the text was drawn with
+    pub font_size: f64,
+}
+
+/// Colors picked for Paint::Auto
+#[derive(Debug)]
+pub struct ChosenColors {
+    /// index of the item in Data::items
+    pub item: usize,
+    /// average color under the textblock
+    pub background: Rgba,
+    /// contrast ratio of the fill to the background, None for gradients and images
+    pub contrast: Option<f64>,
+    pub fill: Option<Rgba>,
+    /// one entry per stroke, None for strokes with another paint
+    pub outlines: Vec<Option<Rgba>>,
+}
+
+/// Smallest fontsize OverflowPolicy::Shrink goes down to
+const MIN_FONT_SIZE: f64 = 1.;
+/// Steps of the search for the fontsize of OverflowPolicy::Shrink
+const SHRINK_STEPS: usize = 8;
+
+#[derive(Debug)]
+pub enum PainterError {
+    Cairo(cairo_rs::Error),
+    /// Text of an item with OverflowPolicy::Error didn't fit
+    Overflow(Overflow),
+    /// Size of the background image couldn't be read
+    Image(ImageError),
+    /// Neither a canvas size nor a background image was given
+    NoSize,
+    /// Svg of an Item::Svg couldn't be parsed
+    Svg(usvg::Error),
+}
+
+impl From<cairo_rs::Error> for PainterError {
+    fn from(error: cairo_rs::Error) -> Self {
+        PainterError::Cairo(error)
+    }
+}
+
+impl From<ImageError> for PainterError {
+    fn from(error: ImageError) -> Self {
+        PainterError::Image(error)
+    }
+}
+
 pub struct Painter {
     surface: Surfaces,
     _context1: Context,
     context2: Context,
     context3: Context,
-    context4: Context,
     pango_context1: pango::Context,
-    pango_context2: pango::Context,
+    overflows: Vec<Overflow>,
+    annotations: Vec<Annotation>,
+    chosen_colors: Vec<ChosenColors>,
+    /// layers of the OpenRaster export
+    capture: Option<Capture>,
 }
 
 impl VerticalAlignment {
@@ -111,8 +193,493 @@ impl Rgb {
     }
 }
 
+impl ColorStop {
+    fn add(&self, gradient: &cairo_rs::Gradient) {
+        gradient.add_color_stop_rgba(
+            self.offset,
+            self.color.r,
+            self.color.g,
+            self.color.b,
+            self.color.a,
+        );
+    }
+}
+
+impl Paint {
+    /// Sets the paint as source of the context, points are relative to origin
+    pub(crate) fn set(&self, context: &Context, origin: &Pos2) -> Result<(), PainterError> {
+        match self {
+            Paint::Solid(color) => context.set_source_rgba(color.r, color.g, color.b, color.a),
+            // picked by Data::painter, black when it is used elsewhere
+            Paint::Auto { .. } => context.set_source_rgb(0., 0., 0.),
+            Paint::LinearGradient(gradient) => {
+                let pattern = cairo_rs::LinearGradient::new(
+                    origin.x + gradient.start.x,
+                    origin.y + gradient.start.y,
+                    origin.x + gradient.end.x,
+                    origin.y + gradient.end.y,
+                );
+                for stop in &gradient.stops {
+                    stop.add(&pattern);
+                }
+                context.set_source(&pattern)?;
+            }
+            Paint::RadialGradient(gradient) => {
+                let pattern = cairo_rs::RadialGradient::new(
+                    origin.x + gradient.inner_center.x,
+                    origin.y + gradient.inner_center.y,
+                    gradient.inner_radius,
+                    origin.x + gradient.outer_center.x,
+                    origin.y + gradient.outer_center.y,
+                    gradient.outer_radius,
+                );
+                for stop in &gradient.stops {
+                    stop.add(&pattern);
+                }
+                context.set_source(&pattern)?;
+            }
+            Paint::Image(image) => {
+                let surface = image_surface(image)?;
+                let pattern = SurfacePattern::create(&surface);
+                pattern.set_extend(Extend::Repeat);
+                pattern.set_matrix(Matrix::new(1., 0., 0., 1., -origin.x, -origin.y));
+                context.set_source(&pattern)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl StrokeJoin {
+    fn to_cairo(&self) -> LineJoin {
+        match self {
+            StrokeJoin::Miter => LineJoin::Miter,
+            StrokeJoin::Round => LineJoin::Round,
+            StrokeJoin::Bevel => LineJoin::Bevel,
+        }
+    }
+}
+
+impl StrokeCap {
+    fn to_cairo(&self) -> LineCap {
+        match self {
+            StrokeCap::Butt => LineCap::Butt,
+            StrokeCap::Round => LineCap::Round,
+            StrokeCap::Square => LineCap::Square,
+        }
+    }
+}
+
+impl Stroke {
+    /// Solid outline with round joins
+    pub fn new(width: f64, paint: Paint) -> Stroke {
+        Stroke {
+            width,
+            paint,
+            join: StrokeJoin::default(),
+            cap: StrokeCap::default(),
+            miter_limit: 10.,
+            dash: vec![],
+            dash_offset: 0.,
+        }
+    }
+
+    /// Sets the source and line settings of the context
+    pub(crate) fn set(&self, context: &Context, origin: &Pos2) -> Result<(), PainterError> {
+        context.set_line_width(self.width);
+        context.set_line_join(self.join.to_cairo());
+        context.set_line_cap(self.cap.to_cairo());
+        context.set_miter_limit(self.miter_limit);
+        context.set_dash(&self.dash, self.dash_offset);
+        self.paint.set(context, origin)
+    }
+}
+
+impl Anchor {
+    fn point(&self, pos: &Pos2, size: &Size2) -> (f64, f64) {
+        match self {
+            Anchor::Center => (pos.x + size.width / 2., pos.y + size.height / 2.),
+            Anchor::TopLeft => (pos.x, pos.y),
+            Anchor::TopRight => (pos.x + size.width, pos.y),
+            Anchor::BottomLeft => (pos.x, pos.y + size.height),
+            Anchor::BottomRight => (pos.x + size.width, pos.y + size.height),
+            Anchor::Custom(point) => (pos.x + point.x, pos.y + point.y),
+        }
+    }
+}
+
+impl Transform {
+    pub(crate) fn set(&self, context: &Context, pos: &Pos2, size: &Size2) {
+        let (x, y) = self.anchor.point(pos, size);
+        context.translate(x, y);
+        if let Some(m) = self.matrix {
+            context.transform(Matrix::new(m[0], m[1], m[2], m[3], m[4], m[5]));
+        }
+        context.rotate(self.rotation.to_radians());
+        context.transform(Matrix::new(
+            1.,
+            self.skew_y.to_radians().tan(),
+            self.skew_x.to_radians().tan(),
+            1.,
+            0.,
+            0.,
+        ));
+        context.translate(-x, -y);
+    }
+}
+
+impl Default for BoxStyle {
+    fn default() -> Self {
+        BoxStyle {
+            padding: Padding::default(),
+            border: None,
+            radius: 0.,
+            opacity: 1.,
+            shape: BoxShape::default(),
+        }
+    }
+}
+
+impl BoxStyle {
+    /// Adds the outline of the box to the path
+    pub(crate) fn path(
+        &self,
+        context: &Context,
+        pos: &Pos2,
+        size: &Size2,
+    ) -> Result<(), cairo_rs::Error> {
+        let (x, y, w, h) = (pos.x, pos.y, size.width, size.height);
+        let radius = match self.shape {
+            BoxShape::Ellipse => {
+                context.save()?;
+                context.translate(x + w / 2., y + h / 2.);
+                context.scale(w / 2., h / 2.);
+                context.new_sub_path();
+                context.arc(0., 0., 1., 0., 2. * PI as f64);
+                return context.restore();
+            }
+            BoxShape::Pill => w.min(h) / 2.,
+            BoxShape::Rectangle => self.radius.clamp(0., w.min(h) / 2.),
+        };
+        if radius <= 0. {
+            context.rectangle(x, y, w, h);
+            return Ok(());
+        }
+        let quarter = PI as f64 / 2.;
+        context.new_sub_path();
+        context.arc(x + w - radius, y + radius, radius, -quarter, 0.);
+        context.arc(x + w - radius, y + h - radius, radius, 0., quarter);
+        context.arc(x + radius, y + h - radius, radius, quarter, 2. * quarter);
+        context.arc(x + radius, y + radius, radius, 2. * quarter, 3. * quarter);
+        context.close_path();
+        Ok(())
+    }
+
+    /// Draws the background and the border of the box
+    fn draw(
+        &self,
+        background: &Background,
+        context: &Context,
+        pos: &Pos2,
+        size: &Size2,
+    ) -> Result<(), PainterError> {
+        context.new_path();
+        self.path(context, pos, size)?;
+        background.fill(context, pos, size, self.opacity)?;
+        self.draw_border(context, pos, |context| self.path(context, pos, size))?;
+        Ok(())
+    }
+
+    /// Strokes the outline that outline adds to the path
+    fn draw_border(
+        &self,
+        context: &Context,
+        pos: &Pos2,
+        outline: impl Fn(&Context) -> Result<(), cairo_rs::Error>,
+    ) -> Result<(), PainterError> {
+        if let Some(border) = &self.border {
+            context.new_path();
+            outline(context)?;
+            border.set(context, pos)?;
+            context.stroke()?;
+        }
+        Ok(())
+    }
+}
+
+impl ImageFilter {
+    fn to_cairo(&self) -> Filter {
+        match self {
+            ImageFilter::Fast => Filter::Fast,
+            ImageFilter::Good => Filter::Good,
+            ImageFilter::Best => Filter::Best,
+            ImageFilter::Nearest => Filter::Nearest,
+            ImageFilter::Bilinear => Filter::Bilinear,
+        }
+    }
+}
+
+impl ImageFit {
+    /// Upper left corner and scale of an image of the size in the box: x, y, scale x, scale y
+    pub(crate) fn place(
+        &self,
+        width: f64,
+        height: f64,
+        pos: &Pos2,
+        size: &Size2,
+    ) -> (f64, f64, f64, f64) {
+        let (scale_x, scale_y) = match self {
+            ImageFit::Stretch => (size.width / width, size.height / height),
+            ImageFit::Contain => {
+                let scale = (size.width / width).min(size.height / height);
+                (scale, scale)
+            }
+            ImageFit::Cover => {
+                let scale = (size.width / width).max(size.height / height);
+                (scale, scale)
+            }
+            ImageFit::Tile | ImageFit::Center => (1., 1.),
+        };
+        let (x, y) = match self {
+            ImageFit::Stretch | ImageFit::Tile => (pos.x, pos.y),
+            _ => (
+                pos.x + (size.width - width * scale_x) / 2.,
+                pos.y + (size.height - height * scale_y) / 2.,
+            ),
+        };
+        (x, y, scale_x, scale_y)
+    }
+}
+
+/// Pattern of the surface fitted into the box, None for an empty box or surface
+/// width, height: size of the surface in user units
+pub(crate) fn fitted_pattern(
+    surface: &ImageSurface,
+    width: f64,
+    height: f64,
+    fit: &ImageFit,
+    filter: &ImageFilter,
+    pos: &Pos2,
+    size: &Size2,
+) -> Option<SurfacePattern> {
+    let (x, y, scale_x, scale_y) = fit.place(width, height, pos, size);
+    // pixels of the surface per unit of the box
+    let xx = surface.width() as f64 / width / scale_x;
+    let yy = surface.height() as f64 / height / scale_y;
+    if !(xx.is_normal() && yy.is_normal()) {
+        return None;
+    }
+    let pattern = SurfacePattern::create(surface);
+    pattern.set_filter(filter.to_cairo());
+    if let ImageFit::Tile = fit {
+        pattern.set_extend(Extend::Repeat);
+    }
+    // maps user space onto the pixels of the surface
+    pattern.set_matrix(Matrix::new(xx, 0., 0., yy, -x * xx, -y * yy));
+    Some(pattern)
+}
+
+impl Item {
+    /// Index of the layer in Data::layers
+    fn layer(&self) -> usize {
+        match self {
+            Item::Text(text) => text.layer,
+            Item::Image(graphic) | Item::Svg(graphic) => graphic.layer,
+            Item::Shape(shape) => shape.layer,
+        }
+    }
+
+    fn blend(&self) -> &BlendMode {
+        match self {
+            Item::Text(text) => &text.blend,
+            Item::Image(graphic) | Item::Svg(graphic) => &graphic.blend,
+            Item::Shape(shape) => &shape.blend,
+        }
+    }
+
+    /// Kind of the item in layer names
+    fn kind(&self) -> &'static str {
+        match self {
+            Item::Text(_) => "text",
+            Item::Image(_) => "image",
+            Item::Svg(_) => "svg",
+            Item::Shape(_) => "shape",
+        }
+    }
+
+    fn z_index(&self) -> i32 {
+        match self {
+            Item::Text(text) => text.z_index,
+            Item::Image(graphic) | Item::Svg(graphic) => graphic.z_index,
+            Item::Shape(shape) => shape.z_index,
+        }
+    }
+}
+
+impl Default for Layer {
+    fn default() -> Self {
+        Layer {
+            name: String::new(),
+            visible: true,
+            opacity: 1.,
+            blend: BlendMode::default(),
+        }
+    }
+}
+
+impl Layer {
+    /// A visible layer that isn't opaque or blended normally is drawn into a group
+    fn grouped(&self) -> bool {
+        self.visible && self.blend.needs_group(self.opacity)
+    }
+
+    /// Starts drawing the items of the layer
+    fn begin(&self, context: &Context) {
+        if self.grouped() {
+            context.push_group();
+        }
+    }
+
+    /// Paints the group of the layer with its opacity and blend mode
+    fn end(&self, context: &Context) -> Result<(), cairo_rs::Error> {
+        if self.grouped() {
+            paint_group(context, self.opacity, &self.blend)?;
+        }
+        Ok(())
+    }
+}
+
+/// Paints the group that was pushed onto the context with the opacity and blend mode
+pub(crate) fn paint_group(
+    context: &Context,
+    opacity: f64,
+    blend: &BlendMode,
+) -> Result<(), cairo_rs::Error> {
+    context.pop_group_to_source()?;
+    context.save()?;
+    context.set_operator(blend.to_cairo());
+    context.paint_with_alpha(opacity.clamp(0., 1.))?;
+    context.restore()
+}
+
+impl BlendMode {
+    /// Whether drawing with the opacity and this blend mode needs a group
+    pub(crate) fn needs_group(&self, opacity: f64) -> bool {
+        opacity < 1. || !matches!(self, BlendMode::Normal)
+    }
+
+    pub(crate) fn to_cairo(&self) -> Operator {
+        match self {
+            BlendMode::Normal => Operator::Over,
+            BlendMode::Multiply => Operator::Multiply,
+            BlendMode::Screen => Operator::Screen,
+            BlendMode::Overlay => Operator::Overlay,
+            BlendMode::Darken => Operator::Darken,
+            BlendMode::Lighten => Operator::Lighten,
+            BlendMode::ColorDodge => Operator::ColorDodge,
+            BlendMode::ColorBurn => Operator::ColorBurn,
+            BlendMode::HardLight => Operator::HardLight,
+            BlendMode::SoftLight => Operator::SoftLight,
+            BlendMode::Difference => Operator::Difference,
+            BlendMode::Exclusion => Operator::Exclusion,
+            BlendMode::Hue => Operator::HslHue,
+            BlendMode::Saturation => Operator::HslSaturation,
+            BlendMode::Color => Operator::HslColor,
+            BlendMode::Luminosity => Operator::HslLuminosity,
+        }
+    }
+}
+
+impl BackgroundImage {
+    /// Width and height of the image in pixels
+    pub fn size(&self) -> Result<Size2, ImageError> {
+        let (width, height) = image::io::Reader::new(Cursor::new(&self.bytes))
+            .with_guessed_format()?
+            .into_dimensions()?;
+        Ok(Size2::new(width as f64, height as f64))
+    }
+
+    /// Fills the path of the context with the image fitted into the box
+    /// Fails if the image can't be decoded
+    fn fill(
+        &self,
+        context: &Context,
+        pos: &Pos2,
+        size: &Size2,
+        opacity: f64,
+    ) -> Result<(), PainterError> {
+        let surface = image_surface(&self.bytes)?;
+        let (width, height) = (surface.width() as f64, surface.height() as f64);
+        let pattern =
+            match fitted_pattern(&surface, width, height, &self.fit, &self.filter, pos, size) {
+                Some(pattern) => pattern,
+                // an empty box or image draws nothing
+                None => {
+                    context.new_path();
+                    return Ok(());
+                }
+            };
+        context.save()?;
+        context.set_source(&pattern)?;
+        context.clip();
+        context.paint_with_alpha(opacity)?;
+        context.restore()?;
+        Ok(())
+    }
+
+    /// Pixels of the image fitted into a canvas of the size
+    fn canvas(&self, width: f64, height: f64) -> Result<image::RgbaImage, PainterError> {
+        let (width, height) = (width.ceil() as i32, height.ceil() as i32);
+        let mut surface = ImageSurface::create(Format::ARgb32, width, height)?;
+        {
+            let context = Context::new(&surface)?;
+            let size = Size2::new(width as f64, height as f64);
+            context.rectangle(0., 0., size.width, size.height);
+            self.fill(&context, &Pos2::new(0., 0.), &size, 1.)?;
+        }
+        let stride = surface.stride() as usize;
+        Ok(to_image(
+            &pixels(&mut surface)?,
+            width as u32,
+            height as u32,
+            stride,
+        ))
+    }
+}
+
+/// Surface with the pixels of an image in any format the image crate decodes
+pub(crate) fn image_surface(bytes: &[u8]) -> Result<ImageSurface, PainterError> {
+    let image = image::load_from_memory(bytes)?.into_rgba8();
+    let mut surface =
+        ImageSurface::create(Format::ARgb32, image.width() as i32, image.height() as i32)?;
+    let stride = surface.stride() as usize;
+    from_image(&image, &mut pixels(&mut surface)?, stride);
+    Ok(surface)
+}
+
+impl Background {
+    /// Fills the path of the context, an image is fitted into the box
+    fn fill(
+        &self,
+        context: &Context,
+        pos: &Pos2,
+        size: &Size2,
+        opacity: f64,
+    ) -> Result<(), PainterError> {
+        match self {
+            Background::Image(image) => image.fill(context, pos, size, opacity)?,
+            Background::Rgb(color) => {
+                context.set_source_rgba(color.r, color.g, color.b, opacity);
+                context.fill()?;
+            }
+            Background::Inpaint(_) | Background::None => context.new_path(),
+        };
+        Ok(())
+    }
+}
+
 impl Styling {
-    fn layouter(&self, pango_context: &pango::Context) -> Layout {
+    pub(crate) fn layouter(&self, pango_context: &pango::Context) -> Layout {
         if self.vertical {
             pango_context.set_base_gravity(pango::Gravity::East);
         } else {
@@ -214,16 +781,19 @@ impl FontStyle {
     }
 }
 
+fn set_font_size(layout: &Layout, size: f64) {
+    if let Some(font) = layout.font_description() {
+        let mut font = font;
+        font.set_size((size * SCALE as f64) as i32);
+        layout.set_font_description(Some(&font));
+    }
+}
+
 impl Text {
-    fn set_font_size(&self, context: &Context, layout: &Layout) {
-        if let Some(font) = layout.font_description() {
-            let mut font = font;
-            font.set_size(self.font_size as i32 * SCALE);
-            layout.set_font_description(Some(&font));
-        }
-        context.set_source_rgb(self.font_color.r, self.font_color.g, self.font_color.b);
+    pub(crate) fn set_font_size(&self, layout: &Layout) {
+        set_font_size(layout, self.font_size);
     }
-    fn set(&self, layout: &Layout, context: &Context, vertical: bool) {
+    pub(crate) fn set(&self, layout: &Layout, context: &Context, vertical: bool) {
         match self.mode {
             Mode::Text => layout.set_text(&self.value),
             Mode::Markup => layout.set_markup(&self.value),
@@ -231,109 +801,423 @@ impl Text {
                 layout.set_markup_with_accel(&self.value, 'c');
             }
         }
-        if vertical {
-            context.translate(
-                self.pos.x + self.size.width / 2.,
-                self.pos.y + self.size.height / 2.,
-            );
+        if self.path.is_some() {
+            layout.set_width(-1);
+            layout.set_height(-1);
+            layout.set_ellipsize(pango::EllipsizeMode::None);
+        } else if vertical {
+            let (pos, size) = self.content();
+            context.translate(pos.x + size.width / 2., pos.y + size.height / 2.);
             context.rotate(90. * (PI as f64 / 180.));
-            context.translate(
-                -self.size.height / 2. - self.pos.x,
-                -self.size.width / 2. - self.pos.y,
-            );
-            layout.set_width(self.size.height.ceil() as i32 * SCALE);
-            layout.set_height(self.size.width.ceil() as i32 * SCALE);
+            context.translate(-size.height / 2. - pos.x, -size.width / 2. - pos.y);
+            layout.set_width(size.height.ceil() as i32 * SCALE);
+            layout.set_height(size.width.ceil() as i32 * SCALE);
         } else {
-            layout.set_width(self.size.width.ceil() as i32 * SCALE);
-            layout.set_height(self.size.height.ceil() as i32 * SCALE);
+            let size = self.content().1;
+            layout.set_width(size.width.ceil() as i32 * SCALE);
+            layout.set_height(size.height.ceil() as i32 * SCALE);
+        }
+        if !self.frames.is_empty() {
+            layout.set_height(-1);
+            layout.set_ellipsize(pango::EllipsizeMode::None);
         }
     }
 
-    fn set_stroke(&self, layout: &Layout, context: &Context) -> Result<(), cairo_rs::Error> {
-        context.set_line_join(LineJoin::Round);
-        context.set_source_rgba(
-            self.outline_color.r,
-            self.outline_color.g,
-            self.outline_color.b,
-            self.outline_color.a,
-        );
+    /// Box inside the padding the text is laid out in
+    pub(crate) fn content(&self) -> (Pos2, Size2) {
+        self.inset(&self.pos, &self.size)
+    }
+
+    /// Box without the padding
+    fn inset(&self, pos: &Pos2, size: &Size2) -> (Pos2, Size2) {
+        let padding = &self.decoration.padding;
+        (
+            Pos2::new(pos.x + padding.left, pos.y + padding.top),
+            Size2::new(
+                (size.width - padding.left - padding.right).max(0.),
+                (size.height - padding.top - padding.bottom).max(0.),
+            ),
+        )
+    }
+
+    /// Box of a frame in the coordinates of the layout
+    /// Vertical text is rotated around the center of the content, frames are moved along
+    pub(crate) fn frame_rect(&self, pos: &Pos2, size: &Size2, vertical: bool) -> Rect {
+        if !vertical {
+            return Rect {
+                x: pos.x,
+                y: pos.y,
+                width: size.width,
+                height: size.height,
+            };
+        }
+        let (content_pos, content_size) = self.content();
+        let dx = pos.x + size.width / 2. - content_pos.x - content_size.width / 2.;
+        let dy = pos.y + size.height / 2. - content_pos.y - content_size.height / 2.;
+        Rect {
+            x: content_pos.x + content_size.height / 2. - size.height / 2. + dy,
+            y: content_pos.y + content_size.width / 2. - size.width / 2. - dx,
+            width: size.height,
+            height: size.width,
+        }
+    }
+
+    /// Draws the box and the frames, or the quad, with their background and border
+    fn draw_background(&self, context: &Context) -> Result<(), PainterError> {
+        context.save()?;
+        self.set_transform(context);
+        if self.quad.is_some() {
+            // the quad is in output coordinates
+            context.identity_matrix();
+            context.new_path();
+            self.quad_path(context)?;
+            self.background
+                .fill(context, &self.pos, &self.size, self.decoration.opacity)?;
+            self.decoration
+                .draw_border(context, &self.pos, |context| self.quad_path(context))?;
+            context.restore()?;
+            return Ok(());
+        }
+        let decoration = &self.decoration;
+        decoration.draw(&self.background, context, &self.pos, &self.size)?;
+        for frame in &self.frames {
+            decoration.draw(&self.background, context, &frame.pos, &frame.size)?;
+        }
+        context.restore()?;
+        Ok(())
+    }
+
+    fn set_transform(&self, context: &Context) {
+        if let Some(transform) = &self.transform {
+            transform.set(context, &self.pos, &self.size);
+        }
+    }
+
+    /// Adds the outline of the text to the path
+    fn trace(&self, runs: &[Run], context: &Context) -> Result<(), cairo_rs::Error> {
+        for run in runs {
+            match &self.path {
+                None => {
+                    context.move_to(run.x, run.y);
+                    match &run.line {
+                        None => layout_path(context, &run.layout),
+                        Some(line) => layout_line_path(context, line),
+                    }
+                }
+                Some(path) => path.trace(&run.layout, context, &self.pos)?,
+            }
+        }
+        if self.projects_paths(context) {
+            self.project_path(context)?;
+        }
+        Ok(())
+    }
+
+    fn set_stroke(
+        &self,
+        runs: &[Run],
+        context: &Context,
+        colors: Option<&ChosenColors>,
+    ) -> Result<(), PainterError> {
+        for (index, stroke) in self.strokes.iter().enumerate() {
+            context.new_path();
+            self.trace(runs, context)?;
+            stroke.set(context, &self.pos)?;
+            if let Some(color) = colors.and_then(|colors| colors.outlines[index].as_ref()) {
+                context.set_source_rgba(color.r, color.g, color.b, color.a);
+            }
+            context.stroke()?;
+        }
+        Ok(())
+    }
+
+    fn set_fill(
+        &self,
+        runs: &[Run],
+        context: &Context,
+        colors: Option<&ChosenColors>,
+    ) -> Result<(), PainterError> {
+        match colors.and_then(|colors| colors.fill.as_ref()) {
+            Some(color) => context.set_source_rgba(color.r, color.g, color.b, color.a),
+            None => self.font_color.set(context, &self.pos)?,
+        }
+        match &self.path {
+            None if !self.projects_paths(context) => {
+                for run in runs {
+                    context.move_to(run.x, run.y);
+                    match &run.line {
+                        None => pangocairo::show_layout(context, &run.layout),
+                        Some(line) => pangocairo::show_layout_line(context, line),
+                    }
+                }
+            }
+            _ => {
+                context.new_path();
+                self.trace(runs, context)?;
+                context.fill()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Draws effects, outlines and fill
+    /// colors: picked colors for Paint::Auto
+    pub(crate) fn paint(
+        &self,
+        runs: &[Run],
+        context: &Context,
+        scale: f64,
+        colors: Option<&ChosenColors>,
+    ) -> Result<(), PainterError> {
+        self.set_effects(runs, context, scale)?;
+        match self.paint_order {
+            PaintOrder::StrokeUnder => {
+                self.set_stroke(runs, context, colors)?;
+                self.set_fill(runs, context, colors)
+            }
+            PaintOrder::StrokeOver => {
+                self.set_fill(runs, context, colors)?;
+                self.set_stroke(runs, context, colors)
+            }
+            PaintOrder::StrokeOnly => self.set_stroke(runs, context, colors),
+            PaintOrder::FillOnly => self.set_fill(runs, context, colors),
+        }
+    }
+
+    fn set_effects(
+        &self,
+        runs: &[Run],
+        context: &Context,
+        scale: f64,
+    ) -> Result<(), cairo_rs::Error> {
+        if self.effects.is_empty() {
+            return Ok(());
+        }
+        context.new_path();
+        self.trace(runs, context)?;
+        let path = context.copy_path()?;
+        let outline = self
+            .strokes
+            .iter()
+            .map(|stroke| stroke.width)
+            .fold(0., f64::max);
+        for effect in &self.effects {
+            effect.draw(context, &path, outline, scale)?;
+        }
+        context.new_path();
+        Ok(())
+    }
+
+    /// Positions the layout in the box, flows it into the region or threads it through the frames
+    /// Returns the text that didn't fit
+    fn runs(
+        &self,
+        layout: &Layout,
+        context: &Context,
+        vertical: bool,
+        align: &Alignments,
+    ) -> Result<(Vec<Run>, Option<String>), cairo_rs::Error> {
+        self.position(layout, context, vertical, align);
+        if self.path.is_some()
+            || (self.frames.is_empty() && matches!(self.region, Region::Rectangle))
+        {
+            let (x, y) = context.current_point()?;
+            let remaining = match self.path {
+                None => self.layout_overflow(layout, vertical),
+                Some(_) => None,
+            };
+            return Ok((
+                vec![Run {
+                    layout: layout.clone(),
+                    line: None,
+                    x,
+                    y,
+                }],
+                remaining,
+            ));
+        }
+        let va = match (vertical, &align.ha) {
+            (false, _) => match align.va {
+                VerticalAlignment::Top => VerticalAlignment::Top,
+                VerticalAlignment::Center => VerticalAlignment::Center,
+                VerticalAlignment::Bottom => VerticalAlignment::Bottom,
+            },
+            (true, HorizontalAlignment::Left) => VerticalAlignment::Bottom,
+            (true, HorizontalAlignment::Center) => VerticalAlignment::Center,
+            (true, HorizontalAlignment::Right) => VerticalAlignment::Top,
+        };
+        context.new_path();
+        let ellipsize = matches!(self.overflow, OverflowPolicy::Ellipsize);
+        if !self.frames.is_empty() {
+            let boxes = std::iter::once((&self.pos, &self.size))
+                .chain(self.frames.iter().map(|frame| (&frame.pos, &frame.size)))
+                .map(|(pos, size)| {
+                    let (pos, size) = self.inset(pos, size);
+                    self.frame_rect(&pos, &size, vertical)
+                })
+                .collect::<Vec<_>>();
+            let (runs, overflow) = thread(layout, &boxes, &va, ellipsize);
+            return Ok((runs, remaining(layout, overflow, ellipsize)));
+        }
+        let (pos, size) = self.content();
+        // the points of a polygon are relative to the textblock, not to the padded content
+        let region = match &self.region {
+            Region::Rectangle => Region::Rectangle,
+            Region::Ellipse => Region::Ellipse,
+            Region::Polygon(points) => Region::Polygon(
+                points
+                    .iter()
+                    .map(|point| {
+                        Pos2::new(point.x + self.pos.x - pos.x, point.y + self.pos.y - pos.y)
+                    })
+                    .collect(),
+            ),
+        };
+        let (runs, overflow) = flow(layout, &region, &pos, &size, vertical, &va, ellipsize);
+        Ok((runs, remaining(layout, overflow, ellipsize)))
+    }
+
+    /// Text of the lines below the box, empty if the text is only too wide or shortened
+    pub(crate) fn layout_overflow(&self, layout: &Layout, vertical: bool) -> Option<String> {
+        let size = self.content().1;
+        let (width, height) = match vertical {
+            true => (size.height, size.width),
+            false => (size.width, size.height),
+        };
+        let mut iter = layout.iter();
+        loop {
+            let (_, logical) = iter.line_extents();
+            if (logical.y() + logical.height()) as f64 / SCALE as f64 > height {
+                return iter
+                    .line_readonly()
+                    .map(|line| layout.text()[line.start_index() as usize..].to_string());
+            }
+            if !iter.next_line() {
+                break;
+            }
+        }
+        let logical = layout.pixel_extents().1;
+        (logical.width() as f64 > width.ceil() || layout.is_ellipsized()).then(String::new)
+    }
+
+    /// Lays out the text and applies the overflow policy
+    /// Returns the placed text, the text that didn't fit and the fontsize
+    pub(crate) fn fit(
+        &self,
+        layout: &Layout,
+        context: &Context,
+        vertical: bool,
+        align: &Alignments,
+    ) -> Result<(Vec<Run>, Option<String>, f64), cairo_rs::Error> {
+        if let OverflowPolicy::Ellipsize = self.overflow {
+            if self.path.is_none()
+                && self.frames.is_empty()
+                && matches!(self.region, Region::Rectangle)
+            {
+                layout.set_ellipsize(pango::EllipsizeMode::End);
+            }
+        }
+        let (runs, remaining) = self.runs(layout, context, vertical, align)?;
+        if remaining.is_none() || !matches!(self.overflow, OverflowPolicy::Shrink) {
+            return Ok((runs, remaining, self.font_size));
+        }
+        let (mut low, mut high) = (MIN_FONT_SIZE, self.font_size);
+        let mut size = MIN_FONT_SIZE;
+        for _ in 0..SHRINK_STEPS {
+            let middle = (low + high) / 2.;
+            set_font_size(layout, middle);
+            match self.runs(layout, context, vertical, align)?.1 {
+                None => {
+                    size = middle;
+                    low = middle;
+                }
+                Some(_) => high = middle,
+            }
+        }
+        set_font_size(layout, size);
+        let (runs, remaining) = self.runs(layout, context, vertical, align)?;
+        Ok((runs, remaining, size))
+    }
 
-        layout_path(context, layout);
-        context.set_line_width(self.font_stroke);
-        context.stroke()?;
+    /// Restricts drawing to the textblock and its frames
+    /// A projected textblock is restricted to its quad
+    fn clip(&self, context: &Context, vertical: bool) -> Result<(), cairo_rs::Error> {
+        context.new_path();
+        match &self.quad {
+            Some(_) => self.quad_path(context)?,
+            None => {
+                let boxes = std::iter::once((&self.pos, &self.size))
+                    .chain(self.frames.iter().map(|frame| (&frame.pos, &frame.size)));
+                for (pos, size) in boxes {
+                    let rect = self.frame_rect(pos, size, vertical);
+                    context.rectangle(rect.x, rect.y, rect.width, rect.height);
+                }
+            }
+        }
+        context.clip();
         Ok(())
     }
 
-    fn align(&self, layout: &Layout, context: &Context, vertical: bool, align: &Alignments) {
-        align
-            .ha
-            .set(layout, context, &self.pos, &self.size, vertical);
-        align
-            .va
-            .set(layout, context, &self.pos, &self.size, vertical);
-        pangocairo::show_layout(context, layout);
+    pub(crate) fn position(
+        &self,
+        layout: &Layout,
+        context: &Context,
+        vertical: bool,
+        align: &Alignments,
+    ) {
+        let (pos, size) = self.content();
+        align.ha.set(layout, context, &pos, &size, vertical);
+        align.va.set(layout, context, &pos, &size, vertical);
     }
 }
 
 impl Painter {
     fn new(
         output_mode: &OutputMode,
-        image: &Option<Vec<u8>>,
+        image: &Option<BackgroundImage>,
         width: f64,
         height: f64,
-    ) -> Result<Painter, cairo_rs::Error> {
-        let mut img = image.as_ref().map(Cursor::new);
-
-        //TODO: replace expect
-        let image_surface = img
-            .as_mut()
-            .map(ImageSurface::create_from_png)
-            .map(|surface| surface.expect("Failed to load image"));
-        let img_some = image_surface.is_some();
-
-        let surface = Painter::new_surface(output_mode, image_surface.clone(), width, height)?;
+    ) -> Result<Painter, PainterError> {
+        let surface = Painter::new_surface(output_mode, width, height)?;
         let context = Painter::new_context(&surface)?;
 
         let context2 = Painter::new_context(&surface)?;
 
         let context3 = Painter::new_context(&surface)?;
-        let context4 = Painter::new_context(&surface)?;
 
-        if (&OutputMode::Png(true) != output_mode || &OutputMode::Png(false) != output_mode)
-            && img_some
-        {
-            context.set_source_surface(&image_surface.unwrap(), 0.0, 0.0)?;
-            context.paint()?;
+        if let Some(image) = image {
+            context.rectangle(0., 0., width, height);
+            image.fill(&context, &Pos2::new(0., 0.), &Size2::new(width, height), 1.)?;
         }
         let pc = create_context(&context3);
-        let pc2 = create_context(&context4);
         Ok(Painter {
             surface,
             _context1: context,
             context2,
             context3,
-            context4,
             pango_context1: pc,
-            pango_context2: pc2,
+            overflows: vec![],
+            annotations: vec![],
+            chosen_colors: vec![],
+            capture: None,
         })
     }
 
     fn new_surface(
         output_mode: &OutputMode,
-        image_surface: Option<ImageSurface>,
         width: f64,
         height: f64,
     ) -> Result<Surfaces, cairo_rs::Error> {
         Ok(match output_mode {
-            OutputMode::Png(_) => Surfaces::Png(match image_surface {
-                Some(v) => v,
-                None => {
-                    ImageSurface::create(Format::ARgb32, width.ceil() as i32, height.ceil() as i32)?
-                }
-            }),
+            OutputMode::Pdf(_) => Surfaces::Pdf(PdfSurface::for_stream(width, height, vec![])?),
             OutputMode::Svg => Surfaces::Svg(SvgSurface::for_stream(width, height, vec![])?),
             OutputMode::Ps => Surfaces::Ps(PsSurface::for_stream(width, height, vec![])?),
-            _ => Surfaces::Pdf(PdfSurface::for_stream(width, height, vec![])?),
+            // raster formats are drawn as png and converted when saved
+            _ => Surfaces::Png(ImageSurface::create(
+                Format::ARgb32,
+                width.ceil() as i32,
+                height.ceil() as i32,
+            )?),
         })
     }
 
@@ -346,6 +1230,67 @@ impl Painter {
         })
     }
 
+    /// Items whose text didn't fit into their boxes
+    pub fn overflows(&self) -> &[Overflow] {
+        &self.overflows
+    }
+
+    /// Boxes of the items, lines, words and glyphs as they were drawn
+    pub fn annotations(&self) -> &[Annotation] {
+        &self.annotations
+    }
+
+    /// Applies the augmentations to raster output, vector output is unchanged
+    /// OpenRaster output is unchanged too, the layers are captured while drawing
+    /// and the merged image has to match them
+    pub fn augment(&self, augmentations: &Augmentations) -> Result<(), cairo_rs::Error> {
+        let surface = match (&self.surface, &self.capture) {
+            (Surfaces::Png(surface), None) => surface,
+            _ => return Ok(()),
+        };
+        // the surface is shared with the contexts, its pixels are edited in a copy
+        let (width, height) = (surface.width(), surface.height());
+        let mut copy = ImageSurface::create(Format::ARgb32, width, height)?;
+        {
+            let context = Context::new(&copy)?;
+            context.set_source_surface(surface, 0., 0.)?;
+            context.set_operator(Operator::Source);
+            context.paint()?;
+        }
+        let stride = copy.stride() as usize;
+        {
+            let mut data = pixels(&mut copy)?;
+            let mut image = to_image(&data, width as u32, height as u32, stride);
+            augmentations.apply(&mut image);
+            from_image(&image, &mut data, stride);
+        }
+        let context = Context::new(surface)?;
+        context.set_source_surface(&copy, 0., 0.)?;
+        context.set_operator(Operator::Source);
+        context.paint()
+    }
+
+    /// Colors picked for the items with Paint::Auto
+    pub fn chosen_colors(&self) -> &[ChosenColors] {
+        &self.chosen_colors
+    }
+
+    /// Size of raster output in pixels
+    fn raster_size(&self) -> Option<(i32, i32)> {
+        match &self.surface {
+            Surfaces::Png(surface) => Some((surface.width(), surface.height())),
+            _ => None,
+        }
+    }
+
+    /// Pixels per device unit of rasterised effects
+    fn raster_scale(&self) -> f64 {
+        match self.surface {
+            Surfaces::Png(_) => 1.,
+            _ => VECTOR_RASTER_SCALE,
+        }
+    }
+
     pub fn export<W: Write>(&self, writer: &mut W) -> Result<(), String> {
         match self.surface {
             Surfaces::Pdf(ref pdf_surface) => {
@@ -356,11 +1301,12 @@ impl Painter {
                     .map_err(|_| "Failed to downcast".to_string())?;
                 writer.write_all(&v).map_err(|e| e.to_string())?;
             }
-            Surfaces::Png(ref image_surface) => {
-                image_surface
+            Surfaces::Png(ref image_surface) => match &self.capture {
+                Some(capture) => capture.write(image_surface, writer)?,
+                None => image_surface
                     .write_to_png(writer)
-                    .map_err(|e| e.to_string())?;
-            }
+                    .map_err(|e| e.to_string())?,
+            },
             Surfaces::Svg(ref svg_surface) => {
                 let v: Vec<u8> = *svg_surface
                     .finish_output_stream()
@@ -412,63 +1358,243 @@ impl Pos2 {
 }
 
 impl Data {
+    /// Draws the items on a canvas of the size
+    /// Without a size the canvas gets the size of the background image,
+    /// with a different size the background is scaled by its fit and filter
     pub fn painter(
         &self,
         output_mode: &OutputMode,
-        width: f64,
-        height: f64,
-    ) -> Result<Painter, cairo_rs::Error> {
-        let painter = Painter::new(output_mode, &self.background, width, height)?;
+        size: Option<&Size2>,
+    ) -> Result<Painter, PainterError> {
+        let (width, height) = match (size, &self.background) {
+            (Some(size), _) => (size.width, size.height),
+            (None, Some(background)) => {
+                let size = background.size()?;
+                (size.width, size.height)
+            }
+            (None, None) => return Err(PainterError::NoSize),
+        };
+        let mut painter = Painter::new(output_mode, &self.background, width, height)?;
+        let mut overflows = vec![];
+        let mut annotations = vec![];
+        let mut chosen_colors = vec![];
         let mut reload = true;
+        let default_layer = Layer::default();
+        let layer = |index: usize| self.layers.get(index).unwrap_or(&default_layer);
         let context = &painter.context2;
+        // background image fitted into the canvas, inpainting changes it
+        let mut source = None;
         for item in &self.items {
-            match &item.background {
-                Background::Bytes(image) => {
-                    //TODO: replace expect
-                    let surface = ImageSurface::create_from_png(&mut Cursor::new(&image))
-                        .expect("Failed to create image surface");
-                    context.set_source_surface(surface, item.pos.x, item.pos.y)?;
-                    context.rectangle(item.pos.x, item.pos.y, item.size.width, item.size.height);
-                    context.fill()?;
+            let item = match item {
+                Item::Text(text) if layer(text.layer).visible => text,
+                _ => continue,
+            };
+            if let (Background::Inpaint(mask), Some(background)) =
+                (&item.background, &self.background)
+            {
+                if source.is_none() {
+                    source = Some(background.canvas(width, height)?);
                 }
-                Background::Rgb(color) => {
-                    context.set_source_rgb(color.r, color.g, color.b);
-                    context.rectangle(item.pos.x, item.pos.y, item.size.width, item.size.height);
-                    context.fill()?;
+                if let Some(image) = &mut source {
+                    mask.clean(image, &item.pos, &item.size, context)?;
+                    for frame in &item.frames {
+                        mask.clean(image, &frame.pos, &frame.size, context)?;
+                    }
                 }
-                Background::None => {}
-            };
+            }
         }
-        let mut layout1 = self.global_style.layouter(&painter.pango_context1);
-        let mut layout2 = self.global_style.layouter(&painter.pango_context2);
-        for item in &self.items {
+        let mut capture = match (output_mode, painter.raster_size()) {
+            (OutputMode::OpenRaster, Some((width, height))) => {
+                Some(Capture::new(width, height, &self.layers))
+            }
+            _ => None,
+        };
+        if let (Some(capture), Surfaces::Png(surface), Some(_)) =
+            (&mut capture, &painter.surface, &self.background)
+        {
+            capture.snapshot(surface, "background".to_string())?;
+        }
+        let context = &painter.context3;
+        let mut layout = self.global_style.layouter(&painter.pango_context1);
+        // layers in order of their index, items of a layer by z-index and then in list order
+        let mut order = (0..self.items.len()).collect::<Vec<_>>();
+        order.sort_by_key(|&index| (self.items[index].layer(), self.items[index].z_index()));
+        let mut current = None;
+        for index in order {
+            let item = &self.items[index];
+            if current != Some(item.layer()) {
+                if let Some(previous) = current {
+                    layer(previous).end(context)?;
+                }
+                layer(item.layer()).begin(context);
+                current = Some(item.layer());
+            }
+            // items of hidden layers are only drawn into the OpenRaster export
+            let hidden = !layer(item.layer()).visible;
+            if hidden && capture.is_none() {
+                continue;
+            }
+            let item = match item {
+                Item::Text(text) => text,
+                graphic => {
+                    if let Some(capture) = &capture {
+                        capture.begin(context);
+                    }
+                    match graphic {
+                        Item::Image(image) => image.draw(false, context, painter.raster_scale())?,
+                        Item::Svg(svg) => svg.draw(true, context, painter.raster_scale())?,
+                        Item::Shape(shape) => shape.draw(context)?,
+                        Item::Text(_) => {}
+                    }
+                    if let Some(capture) = &mut capture {
+                        // the opacity of the item is already applied to its pass
+                        capture.end(
+                            context,
+                            graphic.layer(),
+                            format!("item {} {}", index, graphic.kind()),
+                            1.,
+                            graphic.blend(),
+                            graphic.blend(),
+                        )?;
+                    }
+                    continue;
+                }
+            };
             let vertical: bool;
             match &item.style {
                 None => {
                     vertical = self.global_style.vertical;
                     if reload {
-                        layout1 = self.global_style.layouter(&painter.pango_context1);
-                        layout2 = self.global_style.layouter(&painter.pango_context2);
+                        layout = self.global_style.layouter(&painter.pango_context1);
                         reload = false;
                     }
                 }
                 Some(style) => {
                     vertical = style.vertical;
-                    layout1 = style.layouter(&painter.pango_context1);
-                    layout2 = style.layouter(&painter.pango_context2);
+                    layout = style.layouter(&painter.pango_context1);
                     reload = true;
                 }
             };
             let align = item.align.as_ref().unwrap_or(&self.global_align);
-            item.set(&layout1, &painter.context3, vertical);
-            item.set(&layout2, &painter.context4, vertical);
-            item.set_font_size(&painter.context3, &layout1);
-            item.set_font_size(&painter.context4, &layout2);
-            item.align(&layout1, &painter.context3, vertical, align);
-            item.set_stroke(&layout1, &painter.context3)?;
-            item.align(&layout2, &painter.context4, vertical, align);
+            let style = item.style.as_ref().unwrap_or(&self.global_style);
+            let grouped = item.blend.needs_group(item.opacity);
+            if grouped {
+                context.push_group();
+            }
+            // the passes are combined like the item in the OpenRaster export
+            let normal = BlendMode::Normal;
+            if let Some(capture) = &capture {
+                capture.begin(context);
+            }
+            item.draw_background(context)?;
+            if let Some(capture) = &mut capture {
+                capture.end(
+                    context,
+                    item.layer,
+                    format!("item {} box", index),
+                    item.opacity,
+                    &item.blend,
+                    &normal,
+                )?;
+                capture.begin(context);
+            }
+            context.save()?;
+            item.set_transform(context);
+            style.ellipsize.set(&layout);
+            item.set(&layout, context, vertical);
+            item.set_font_size(&layout);
+            let (runs, remaining, font_size) = item.fit(&layout, context, vertical, align)?;
+            if let (Some(remaining), OverflowPolicy::Error, false) =
+                (&remaining, &item.overflow, hidden)
+            {
+                return Err(PainterError::Overflow(Overflow {
+                    item: index,
+                    remaining: remaining.to_string(),
+                    font_size,
+                }));
+            }
+            if !hidden {
+                annotations.push(item.annotate(index, &runs, context, vertical)?);
+            }
+            if let OverflowPolicy::Clip = item.overflow {
+                item.clip(context, vertical)?;
+            }
+            let colors = match item.has_auto_colors() {
+                true => {
+                    if let (true, Some(background)) = (source.is_none(), &self.background) {
+                        source = Some(background.canvas(width, height)?);
+                    }
+                    Some(item.choose_colors(index, source.as_ref()))
+                }
+                false => None,
+            };
+            match (&item.quad, painter.raster_size()) {
+                (Some(_), Some((width, height))) => {
+                    item.paint_projected(&runs, context, width, height, colors.as_ref())?
+                }
+                _ => item.paint(&runs, context, painter.raster_scale(), colors.as_ref())?,
+            }
+            context.restore()?;
+            if let Some(capture) = &mut capture {
+                capture.end(
+                    context,
+                    item.layer,
+                    format!("item {} text", index),
+                    item.opacity,
+                    &item.blend,
+                    &normal,
+                )?;
+            }
+            if grouped {
+                paint_group(context, item.opacity, &item.blend)?;
+            }
+            if hidden {
+                continue;
+            }
+            chosen_colors.extend(colors);
+            if let Some(remaining) = remaining {
+                overflows.push(Overflow {
+                    item: index,
+                    remaining,
+                    font_size,
+                });
+            }
+        }
+        if let Some(previous) = current {
+            layer(previous).end(context)?;
         }
+        painter.overflows = overflows;
+        painter.annotations = annotations;
+        painter.chosen_colors = chosen_colors;
+        painter.capture = capture;
 
         Ok(painter)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use cairo_rs::{Context, Format, ImageSurface};
+
+    use super::PainterError;
+    use crate::input::{ImageFit, Paint, Pos2, Size2};
+
+    #[test]
+    fn place_fits_a_wide_image_into_a_square() {
+        let (pos, size) = (Pos2::new(10., 20.), Size2::new(100., 100.));
+        let place = |fit: ImageFit| fit.place(200., 100., &pos, &size);
+        assert_eq!(place(ImageFit::Stretch), (10., 20., 0.5, 1.));
+        assert_eq!(place(ImageFit::Contain), (10., 45., 0.5, 0.5));
+        assert_eq!(place(ImageFit::Cover), (-40., 20., 1., 1.));
+        assert_eq!(place(ImageFit::Tile), (10., 20., 1., 1.));
+        assert_eq!(place(ImageFit::Center), (-40., 20., 1., 1.));
+    }
+
+    #[test]
+    fn undecodable_image_paint_is_an_error() {
+        let surface = ImageSurface::create(Format::ARgb32, 4, 4).unwrap();
+        let context = Context::new(&surface).unwrap();
+        let result = Paint::Image(vec![1, 2, 3]).set(&context, &Pos2::new(0., 0.));
+        assert!(matches!(result, Err(PainterError::Image(_))));
+    }
+}
@@ -0,0 +1,171 @@
+use image::RgbaImage;
+
+use crate::cairopango::ChosenColors;
+use crate::input::{Background, Paint, Rgba, Text};
+
+/// Steps of the search for the smallest change of the background color
+const SEARCH_STEPS: usize = 16;
+
+/// Relative luminance of a color with channels from 0 to 1
+fn luminance(color: [f64; 3]) -> f64 {
+    let linear = color.map(|c| match c <= 0.04045 {
+        true => c / 12.92,
+        false => ((c + 0.055) / 1.055).powf(2.4),
+    });
+    0.2126 * linear[0] + 0.7152 * linear[1] + 0.0722 * linear[2]
+}
+
+/// WCAG contrast ratio of two luminances, 1 to 21
+fn ratio(a: f64, b: f64) -> f64 {
+    (a.max(b) + 0.05) / (a.min(b) + 0.05)
+}
+
+/// Moves the color towards black or white until it has the contrast to the luminance
+/// Goes in the direction with more contrast and stops at black or white
+fn contrasting(base: [f64; 3], against: f64, target: f64) -> [f64; 3] {
+    let towards = match ratio(1., against) >= ratio(0., against) {
+        true => 1.,
+        false => 0.,
+    };
+    let mix = |t: f64| base.map(|c| c + (towards - c) * t);
+    if ratio(luminance(base), against) >= target {
+        return base;
+    }
+    let (mut low, mut high) = (0., 1.);
+    for _ in 0..SEARCH_STEPS {
+        let middle = (low + high) / 2.;
+        match ratio(luminance(mix(middle)), against) >= target {
+            true => high = middle,
+            false => low = middle,
+        }
+    }
+    mix(high)
+}
+
+impl Text {
+    /// Whether the fill or an outline is picked from the background
+    pub(crate) fn has_auto_colors(&self) -> bool {
+        matches!(self.font_color, Paint::Auto { .. })
+            || self
+                .strokes
+                .iter()
+                .any(|stroke| matches!(stroke.paint, Paint::Auto { .. }))
+    }
+
+    /// Average color under the textblock, a transparent background counts as white
+    fn background_color(&self, image: Option<&RgbaImage>) -> [f64; 3] {
+        let below = self.image_color(image);
+        match &self.background {
+            Background::Rgb(color) => {
+                let opacity = self.decoration.opacity.clamp(0., 1.);
+                let color = [color.r, color.g, color.b].map(|c| c.clamp(0., 1.));
+                [0, 1, 2].map(|i| color[i] * opacity + below[i] * (1. - opacity))
+            }
+            _ => below,
+        }
+    }
+
+    /// Average color of the background image under the textblock
+    fn image_color(&self, image: Option<&RgbaImage>) -> [f64; 3] {
+        let image = match image {
+            Some(image) => image,
+            None => return [1.; 3],
+        };
+        let (width, height) = image.dimensions();
+        let left = self.pos.x.floor().clamp(0., width as f64) as u32;
+        let top = self.pos.y.floor().clamp(0., height as f64) as u32;
+        let right = (self.pos.x + self.size.width)
+            .ceil()
+            .clamp(0., width as f64) as u32;
+        let bottom = (self.pos.y + self.size.height)
+            .ceil()
+            .clamp(0., height as f64) as u32;
+        let mut sum = [0.; 3];
+        let mut count = 0.;
+        for y in top..bottom {
+            for x in left..right {
+                let [r, g, b, a] = image.get_pixel(x, y).0.map(|c| c as f64 / 255.);
+                for (s, c) in sum.iter_mut().zip([r, g, b]) {
+                    *s += c * a + (1. - a);
+                }
+                count += 1.;
+            }
+        }
+        match count > 0. {
+            true => sum.map(|s| s / count),
+            false => [1.; 3],
+        }
+    }
+
+    /// Picks the automatic fill and outline colors
+    /// The fill contrasts with the background, the outlines contrast with the fill
+    pub(crate) fn choose_colors(&self, item: usize, image: Option<&RgbaImage>) -> ChosenColors {
+        let background = self.background_color(image);
+        let background_luminance = luminance(background);
+        let fill = match &self.font_color {
+            Paint::Auto { contrast } => {
+                Some(contrasting(background, background_luminance, *contrast))
+            }
+            _ => None,
+        };
+        let fill_luminance = match (&self.font_color, fill) {
+            (_, Some(fill)) => Some(luminance(fill)),
+            (Paint::Solid(color), None) => Some(luminance([color.r, color.g, color.b])),
+            _ => None,
+        };
+        let outlines = self
+            .strokes
+            .iter()
+            .map(|stroke| match stroke.paint {
+                Paint::Auto { contrast } => Some(contrasting(
+                    background,
+                    fill_luminance.unwrap_or(background_luminance),
+                    contrast,
+                )),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let rgba = |c: [f64; 3]| Rgba::new(c[0], c[1], c[2], 1.);
+        ChosenColors {
+            item,
+            background: rgba(background),
+            contrast: fill_luminance.map(|fill| ratio(fill, background_luminance)),
+            fill: fill.map(rgba),
+            outlines: outlines.into_iter().map(|c| c.map(rgba)).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{contrasting, luminance, ratio};
+
+    #[test]
+    fn ratio_matches_wcag() {
+        assert!((ratio(luminance([1.; 3]), luminance([0.; 3])) - 21.).abs() < 1e-9);
+        assert_eq!(ratio(0.3, 0.3), 1.);
+        // #777777 and #ff0000 on white
+        let gray = luminance([0x77 as f64 / 255.; 3]);
+        assert!((ratio(gray, 1.) - 4.48).abs() < 0.01);
+        assert!((ratio(luminance([1., 0., 0.]), 1.) - 4.).abs() < 0.01);
+    }
+
+    #[test]
+    fn contrasting_reaches_the_target() {
+        let darker = contrasting([0.5; 3], 1., 4.5);
+        let reached = ratio(luminance(darker), 1.);
+        assert!((4.5..4.6).contains(&reached), "{}", reached);
+        assert!(darker.iter().all(|&c| c < 0.5));
+
+        let lighter = contrasting([0.2; 3], 0., 7.);
+        assert!(ratio(luminance(lighter), 0.) >= 7.);
+        assert!(lighter.iter().all(|&c| c > 0.2));
+    }
+
+    #[test]
+    fn contrasting_keeps_colors_with_enough_contrast() {
+        assert_eq!(contrasting([0.; 3], 1., 4.5), [0.; 3]);
+        // the target can't be reached, the color ends at black
+        assert_eq!(contrasting([0.5; 3], 1., 30.), [0.; 3]);
+    }
+}
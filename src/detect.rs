@@ -0,0 +1,362 @@
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+use image::{GrayImage, ImageError};
+
+use crate::input::{Pos2, Region, Size2};
+
+/// Aspect ratios (height / width) tried for the inscribed ellipse
+const ASPECTS: [f64; 9] = [0.25, 0.35, 0.5, 0.7, 1., 1.4, 2., 2.8, 4.];
+/// Points checked on the outline of a candidate ellipse
+const ELLIPSE_SAMPLES: usize = 72;
+
+/// Where the bubble is on the background image
+pub enum BubbleSeed {
+    /// a point inside the bubble
+    Point(Pos2),
+    /// rough box around the bubble, the fill stays inside it
+    /// and starts at the light pixel nearest to its center, which is often covered by lettering
+    Rect { pos: Pos2, size: Size2 },
+}
+
+/// Shape that is fitted into the bubble
+pub enum BubbleShape {
+    Rectangle,
+    Ellipse,
+}
+
+/// Textblock that fits into a bubble
+pub struct Bubble {
+    /// upper left corner for Text::pos
+    pub pos: Pos2,
+    /// width and height for Text::size
+    pub size: Size2,
+    /// region for Text::region
+    pub region: Region,
+}
+
+/// Finds the light area around the seed on the background image and fits the shape into it
+/// light: smallest brightness from 0 to 255 of pixels inside the bubble
+/// Lettering inside the bubble is counted as part of it
+/// Returns None if the seed is not on a light pixel or the rectangle has none
+pub fn detect_bubble(
+    background: &[u8],
+    seed: &BubbleSeed,
+    shape: &BubbleShape,
+    light: u8,
+) -> Result<Option<Bubble>, ImageError> {
+    let image = image::load_from_memory(background)?.to_luma8();
+    let (width, height) = image.dimensions();
+    let (start, bounds) = match seed {
+        BubbleSeed::Point(point) => ((point.x, point.y), (0, 0, width, height)),
+        BubbleSeed::Rect { pos, size } => {
+            let clamp = |v: f64, max: u32| v.floor().clamp(0., max as f64) as u32;
+            let bounds = (
+                clamp(pos.x, width),
+                clamp(pos.y, height),
+                clamp(pos.x + size.width, width),
+                clamp(pos.y + size.height, height),
+            );
+            let center = (pos.x + size.width / 2., pos.y + size.height / 2.);
+            match nearest_light(&image, center, bounds, light) {
+                Some(start) => (start, bounds),
+                None => return Ok(None),
+            }
+        }
+    };
+    let mask = match flood_fill(&image, start, bounds, light) {
+        Some(mask) => fill_holes(mask),
+        None => return Ok(None),
+    };
+    Ok(Some(match shape {
+        BubbleShape::Rectangle => {
+            let (x, y, w, h) = largest_rectangle(&mask);
+            Bubble {
+                pos: Pos2::new((mask.left + x) as f64, (mask.top + y) as f64),
+                size: Size2::new(w as f64, h as f64),
+                region: Region::Rectangle,
+            }
+        }
+        BubbleShape::Ellipse => {
+            let (cx, cy, a, b) = largest_ellipse(&mask);
+            Bubble {
+                pos: Pos2::new(mask.left as f64 + cx - a, mask.top as f64 + cy - b),
+                size: Size2::new(a * 2., b * 2.),
+                region: Region::Ellipse,
+            }
+        }
+    }))
+}
+
+/// Pixels of the bubble inside its bounding box
+struct Mask {
+    left: u32,
+    top: u32,
+    width: u32,
+    height: u32,
+    inside: Vec<bool>,
+}
+
+impl Mask {
+    fn get(&self, x: i64, y: i64) -> bool {
+        x >= 0
+            && y >= 0
+            && x < self.width as i64
+            && y < self.height as i64
+            && self.inside[(y * self.width as i64 + x) as usize]
+    }
+}
+
+/// Center of the light pixel inside the bounds that is nearest to point
+fn nearest_light(
+    image: &GrayImage,
+    point: (f64, f64),
+    bounds: (u32, u32, u32, u32),
+    light: u8,
+) -> Option<(f64, f64)> {
+    let (left, top, right, bottom) = bounds;
+    (top..bottom)
+        .flat_map(|y| (left..right).map(move |x| (x, y)))
+        .filter(|&(x, y)| image.get_pixel(x, y).0[0] >= light)
+        .map(|(x, y)| (x as f64 + 0.5, y as f64 + 0.5))
+        .min_by(|a, b| {
+            let distance = |p: &(f64, f64)| (p.0 - point.0).powi(2) + (p.1 - point.1).powi(2);
+            distance(a).total_cmp(&distance(b))
+        })
+}
+
+/// Light pixels connected to start inside the bounds: left, top, right, bottom
+fn flood_fill(
+    image: &GrayImage,
+    start: (f64, f64),
+    bounds: (u32, u32, u32, u32),
+    light: u8,
+) -> Option<Mask> {
+    let (left, top, right, bottom) = bounds;
+    let (sx, sy) = (start.0.floor() as i64, start.1.floor() as i64);
+    let within = |x: i64, y: i64| {
+        x >= left as i64 && y >= top as i64 && x < right as i64 && y < bottom as i64
+    };
+    if !within(sx, sy) || image.get_pixel(sx as u32, sy as u32).0[0] < light {
+        return None;
+    }
+    let width = (right - left) as usize;
+    let mut filled = vec![false; width * (bottom - top) as usize];
+    let index = |x: i64, y: i64| (y - top as i64) as usize * width + (x - left as i64) as usize;
+    let mut queue = VecDeque::from([(sx, sy)]);
+    filled[index(sx, sy)] = true;
+    let (mut min_x, mut min_y, mut max_x, mut max_y) = (sx, sy, sx, sy);
+    while let Some((x, y)) = queue.pop_front() {
+        min_x = min_x.min(x);
+        min_y = min_y.min(y);
+        max_x = max_x.max(x);
+        max_y = max_y.max(y);
+        for (nx, ny) in [(x - 1, y), (x + 1, y), (x, y - 1), (x, y + 1)] {
+            if within(nx, ny)
+                && !filled[index(nx, ny)]
+                && image.get_pixel(nx as u32, ny as u32).0[0] >= light
+            {
+                filled[index(nx, ny)] = true;
+                queue.push_back((nx, ny));
+            }
+        }
+    }
+
+    let (mask_width, mask_height) = ((max_x - min_x + 1) as u32, (max_y - min_y + 1) as u32);
+    let mut inside = Vec::with_capacity((mask_width * mask_height) as usize);
+    for y in min_y..=max_y {
+        for x in min_x..=max_x {
+            inside.push(filled[index(x, y)]);
+        }
+    }
+    Some(Mask {
+        left: min_x as u32,
+        top: min_y as u32,
+        width: mask_width,
+        height: mask_height,
+        inside,
+    })
+}
+
+/// Adds the areas that are enclosed by the bubble, like its lettering
+fn fill_holes(mut mask: Mask) -> Mask {
+    let (width, height) = (mask.width as i64, mask.height as i64);
+    let mut outside = vec![false; mask.inside.len()];
+    let mut queue = VecDeque::new();
+    for x in 0..width {
+        queue.extend([(x, 0), (x, height - 1)]);
+    }
+    for y in 0..height {
+        queue.extend([(0, y), (width - 1, y)]);
+    }
+    while let Some((x, y)) = queue.pop_front() {
+        if x < 0 || y < 0 || x >= width || y >= height {
+            continue;
+        }
+        let i = (y * width + x) as usize;
+        if outside[i] || mask.inside[i] {
+            continue;
+        }
+        outside[i] = true;
+        queue.extend([(x - 1, y), (x + 1, y), (x, y - 1), (x, y + 1)]);
+    }
+    for (inside, outside) in mask.inside.iter_mut().zip(outside) {
+        *inside = !outside;
+    }
+    mask
+}
+
+/// Largest axis aligned rectangle inside the mask: x, y, width, height
+fn largest_rectangle(mask: &Mask) -> (u32, u32, u32, u32) {
+    let width = mask.width as usize;
+    let mut heights = vec![0usize; width];
+    let mut best = (0, 0, 0, 0);
+    let mut best_area = 0;
+    for y in 0..mask.height as usize {
+        for (x, height) in heights.iter_mut().enumerate() {
+            *height = match mask.inside[y * width + x] {
+                true => *height + 1,
+                false => 0,
+            };
+        }
+        // largest rectangle in the histogram of the row
+        let mut stack: Vec<usize> = vec![];
+        for x in 0..=width {
+            let current = heights.get(x).copied().unwrap_or(0);
+            while let Some(&top) = stack.last() {
+                if heights[top] < current {
+                    break;
+                }
+                stack.pop();
+                let left = stack.last().map_or(0, |&l| l + 1);
+                let area = heights[top] * (x - left);
+                if area > best_area {
+                    best_area = area;
+                    best = (left, y + 1 - heights[top], x - left, heights[top]);
+                }
+            }
+            stack.push(x);
+        }
+    }
+    (best.0 as u32, best.1 as u32, best.2 as u32, best.3 as u32)
+}
+
+/// Largest axis aligned ellipse inside the mask: center x, center y, radius x, radius y
+fn largest_ellipse(mask: &Mask) -> (f64, f64, f64, f64) {
+    let fits = |cx: f64, cy: f64, a: f64, b: f64| {
+        (0..ELLIPSE_SAMPLES).all(|i| {
+            let angle = i as f64 / ELLIPSE_SAMPLES as f64 * 2. * PI;
+            let (x, y) = (cx + a * angle.cos(), cy + b * angle.sin());
+            mask.get(x.floor() as i64, y.floor() as i64)
+        }) && mask.get(cx.floor() as i64, cy.floor() as i64)
+    };
+    let (rx, ry, rw, rh) = largest_rectangle(mask);
+    let mut centers = vec![
+        (rx as f64 + rw as f64 / 2., ry as f64 + rh as f64 / 2.),
+        (mask.width as f64 / 2., mask.height as f64 / 2.),
+    ];
+    let count = mask.inside.iter().filter(|&&inside| inside).count() as f64;
+    if count > 0. {
+        let (mut sx, mut sy) = (0., 0.);
+        for (i, _) in mask.inside.iter().enumerate().filter(|(_, &inside)| inside) {
+            sx += (i % mask.width as usize) as f64 + 0.5;
+            sy += (i / mask.width as usize) as f64 + 0.5;
+        }
+        centers.push((sx / count, sy / count));
+    }
+
+    let mut best = (centers[0].0, centers[0].1, 0., 0.);
+    for &(cx, cy) in &centers {
+        for aspect in ASPECTS {
+            // radius x of the largest fitting ellipse with this aspect ratio
+            let (mut low, mut high) = (0., mask.width.max(mask.height) as f64);
+            for _ in 0..20 {
+                let middle = (low + high) / 2.;
+                match fits(cx, cy, middle, middle * aspect) {
+                    true => low = middle,
+                    false => high = middle,
+                }
+            }
+            if low * low * aspect > best.2 * best.3 {
+                best = (cx, cy, low, low * aspect);
+            }
+        }
+    }
+    best
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{DynamicImage, GrayImage, ImageOutputFormat, Luma};
+
+    use super::{detect_bubble, largest_ellipse, largest_rectangle, BubbleSeed, BubbleShape, Mask};
+    use crate::input::{Pos2, Size2};
+
+    fn mask(width: u32, height: u32, inside: impl Fn(u32, u32) -> bool) -> Mask {
+        Mask {
+            left: 0,
+            top: 0,
+            width,
+            height,
+            inside: (0..width * height)
+                .map(|i| inside(i % width, i / width))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn largest_rectangle_of_a_cross() {
+        // a column on the left and a wider block joined to it
+        let cross = mask(10, 8, |x, y| {
+            x < 2 || ((2..8).contains(&x) && (1..5).contains(&y))
+        });
+        assert_eq!(largest_rectangle(&cross), (0, 1, 8, 4));
+        assert_eq!(largest_rectangle(&mask(4, 4, |_, _| true)), (0, 0, 4, 4));
+        assert_eq!(largest_rectangle(&mask(4, 4, |_, _| false)), (0, 0, 0, 0));
+    }
+
+    #[test]
+    fn largest_ellipse_of_a_disc() {
+        let disc = mask(30, 30, |x, y| {
+            let (dx, dy) = (x as f64 + 0.5 - 15., y as f64 + 0.5 - 15.);
+            dx * dx + dy * dy <= 100.
+        });
+        let (cx, cy, a, b) = largest_ellipse(&disc);
+        assert!((cx - 15.).abs() <= 1. && (cy - 15.).abs() <= 1.);
+        assert!(a > 8. && a <= 10.5, "{}", a);
+        assert!(b > 8. && b <= 10.5, "{}", b);
+    }
+
+    #[test]
+    fn largest_ellipse_follows_the_aspect_of_the_mask() {
+        let wide = mask(40, 10, |_, _| true);
+        let (_, _, a, b) = largest_ellipse(&wide);
+        assert!(a > b * 2.);
+        assert!(b <= 5.);
+    }
+
+    #[test]
+    fn rect_seed_starts_beside_lettering_in_the_center() {
+        // white bubble from 4 to 16 on black, with dark lettering over its center
+        let image = GrayImage::from_fn(20, 20, |x, y| {
+            let bubble = (4..16).contains(&x) && (4..16).contains(&y);
+            let lettering = (7..13).contains(&x) && (8..12).contains(&y);
+            Luma([if bubble && !lettering { 255 } else { 0 }])
+        });
+        let mut png = Cursor::new(vec![]);
+        DynamicImage::ImageLuma8(image)
+            .write_to(&mut png, ImageOutputFormat::Png)
+            .unwrap();
+        let seed = BubbleSeed::Rect {
+            pos: Pos2::new(2., 2.),
+            size: Size2::new(16., 16.),
+        };
+        let bubble = detect_bubble(png.get_ref(), &seed, &BubbleShape::Rectangle, 128)
+            .unwrap()
+            .unwrap();
+        assert_eq!((bubble.pos.x, bubble.pos.y), (4., 4.));
+        assert_eq!((bubble.size.width, bubble.size.height), (12., 12.));
+    }
+}
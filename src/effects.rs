@@ -0,0 +1,162 @@
+use cairo_rs::{BorrowError, Context, Format, ImageSurface, ImageSurfaceData, LineJoin, Path};
+
+use crate::input::Effect;
+
+/// Effects in vector outputs are rasterised at 300 dpi, one device unit is 1/72 inch
+pub(crate) const VECTOR_RASTER_SCALE: f64 = 300. / 72.;
+
+impl Effect {
+    /// Draws the effect for the text outline in path
+    /// outline: thickness of the widest stroke, the silhouette grows by it
+    /// scale: resolution of the rasterised effect in pixels per device unit
+    pub(crate) fn draw(
+        &self,
+        context: &Context,
+        path: &Path,
+        outline: f64,
+        scale: f64,
+    ) -> Result<(), cairo_rs::Error> {
+        let (offset, color, blur, spread) = match self {
+            Effect::DropShadow {
+                offset,
+                color,
+                blur,
+                spread,
+            } => ((offset.x, offset.y), color, *blur, *spread),
+            Effect::Glow {
+                color,
+                blur,
+                spread,
+            } => ((0., 0.), color, *blur, *spread),
+        };
+        let grow = outline + spread * 2.;
+
+        context.new_path();
+        context.append_path(path);
+        let (x1, y1, x2, y2) = context.fill_extents()?;
+        context.new_path();
+        let corners =
+            [(x1, y1), (x2, y1), (x1, y2), (x2, y2)].map(|(x, y)| context.user_to_device(x, y));
+        let margin = grow / 2. + blur * 3. + 1.;
+        let left = corners.iter().map(|c| c.0).fold(f64::INFINITY, f64::min) - margin;
+        let top = corners.iter().map(|c| c.1).fold(f64::INFINITY, f64::min) - margin;
+        let right = corners
+            .iter()
+            .map(|c| c.0)
+            .fold(f64::NEG_INFINITY, f64::max)
+            + margin;
+        let bottom = corners
+            .iter()
+            .map(|c| c.1)
+            .fold(f64::NEG_INFINITY, f64::max)
+            + margin;
+        let width = ((right - left) * scale).ceil() as i32;
+        let height = ((bottom - top) * scale).ceil() as i32;
+        if width <= 0 || height <= 0 {
+            return Ok(());
+        }
+
+        let mut mask = ImageSurface::create(Format::A8, width, height)?;
+        {
+            let mask_context = Context::new(&mask)?;
+            mask_context.scale(scale, scale);
+            mask_context.translate(-left, -top);
+            mask_context.transform(context.matrix());
+            mask_context.append_path(path);
+            mask_context.fill_preserve()?;
+            if grow > 0. {
+                mask_context.set_line_join(LineJoin::Round);
+                mask_context.set_line_width(grow);
+                mask_context.stroke()?;
+            }
+        }
+        let stride = mask.stride() as usize;
+        gaussian_blur(
+            &mut pixels(&mut mask)?,
+            width as usize,
+            height as usize,
+            stride,
+            1,
+            blur * scale,
+        );
+
+        context.save()?;
+        context.identity_matrix();
+        context.set_source_rgba(color.r, color.g, color.b, color.a);
+        context.translate(left + offset.0, top + offset.1);
+        context.scale(1. / scale, 1. / scale);
+        context.mask_surface(&mask, 0., 0.)?;
+        context.restore()
+    }
+}
+
+/// Gives access to the pixels of a surface that is not used by any context
+pub(crate) fn pixels(surface: &mut ImageSurface) -> Result<ImageSurfaceData<'_>, cairo_rs::Error> {
+    surface.data().map_err(|e| match e {
+        BorrowError::Cairo(e) => e,
+        BorrowError::NonExclusive => cairo_rs::Error::SurfaceTypeMismatch,
+    })
+}
+
+/// Blurs every channel of the pixels with a gaussian kernel
+/// sigma: standard deviation of the kernel in pixels
+pub(crate) fn gaussian_blur(
+    data: &mut [u8],
+    width: usize,
+    height: usize,
+    stride: usize,
+    channels: usize,
+    sigma: f64,
+) {
+    if sigma <= 0. || width == 0 || height == 0 {
+        return;
+    }
+    let radius = (sigma * 3.).ceil() as isize;
+    let kernel = (-radius..=radius)
+        .map(|i| (-((i * i) as f64) / (2. * sigma * sigma)).exp() as f32)
+        .collect::<Vec<_>>();
+    let sum = kernel.iter().sum::<f32>();
+    let kernel = kernel.iter().map(|k| k / sum).collect::<Vec<_>>();
+
+    let mut buffer = vec![0f32; width * height * channels];
+    for y in 0..height {
+        for x in 0..width {
+            for c in 0..channels {
+                let mut acc = 0.;
+                for (k, weight) in kernel.iter().enumerate() {
+                    let sx = (x as isize + k as isize - radius).clamp(0, width as isize - 1);
+                    acc += data[y * stride + sx as usize * channels + c] as f32 * weight;
+                }
+                buffer[(y * width + x) * channels + c] = acc;
+            }
+        }
+    }
+    for y in 0..height {
+        for x in 0..width {
+            for c in 0..channels {
+                let mut acc = 0.;
+                for (k, weight) in kernel.iter().enumerate() {
+                    let sy = (y as isize + k as isize - radius).clamp(0, height as isize - 1);
+                    acc += buffer[(sy as usize * width + x) * channels + c] * weight;
+                }
+                data[y * stride + x * channels + c] = acc.round().clamp(0., 255.) as u8;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::gaussian_blur;
+
+    #[test]
+    fn blur_spreads_and_keeps_intensity() {
+        let mut data = vec![0u8; 9 * 9];
+        data[4 * 9 + 4] = 255;
+        gaussian_blur(&mut data, 9, 9, 9, 1, 1.);
+        assert!(data[4 * 9 + 4] < 255);
+        assert!(data[4 * 9 + 5] > 0);
+        let sum = data.iter().map(|&v| v as i32).sum::<i32>();
+        assert!((sum - 255).abs() < 20);
+    }
+}
@@ -0,0 +1,470 @@
+use pango::{Layout, LayoutLine, SCALE};
+
+use crate::cairopango::Run;
+use crate::input::{Pos2, Region, Size2, VerticalAlignment};
+
+/// Line of a shaped flow in box coordinates
+struct Line {
+    layout: Layout,
+    /// byte index of the first character in the text
+    start: usize,
+    x: f64,
+    y: f64,
+    width: f64,
+    height: f64,
+}
+
+/// Lays out the text of layout line by line into the region of the box
+/// The points of a polygon are relative to pos
+/// The width of every line is the space inside the region at its height
+/// Lines that don't fit into the region continue below it with the width of the box
+/// ellipsize: the lines outside of the region are left out, the last one inside ends with ...
+/// Returns the lines and the byte index of the first line outside of the region
+pub(crate) fn flow(
+    layout: &Layout,
+    region: &Region,
+    pos: &Pos2,
+    size: &Size2,
+    vertical: bool,
+    va: &VerticalAlignment,
+    ellipsize: bool,
+) -> (Vec<Run>, Option<usize>) {
+    let (width, height) = match vertical {
+        true => (size.height, size.width),
+        false => (size.width, size.height),
+    };
+    let polygon = match region {
+        Region::Polygon(points) => points
+            .iter()
+            .map(|point| match vertical {
+                true => (point.y, size.width - point.x),
+                false => (point.x, point.y),
+            })
+            .collect(),
+        _ => vec![],
+    };
+    let span = |top: f64, bottom: f64| match region {
+        Region::Rectangle => (top >= 0. && bottom <= height).then_some((0., width)),
+        Region::Ellipse => {
+            let (a, b) = (width / 2., height / 2.);
+            let half = |y: f64| {
+                let t = (y - b) / b;
+                (t.abs() <= 1.).then(|| a * (1. - t * t).sqrt())
+            };
+            let half = half(top)?.min(half(bottom)?);
+            Some((a - half, a + half))
+        }
+        Region::Polygon(_) => polygon_span(&polygon, top, bottom),
+    };
+
+    let mut top = 0.;
+    let (mut lines, mut overflow) = break_lines(layout, &span, top, width, height);
+    if !matches!(va, VerticalAlignment::Top) {
+        // the width of the lines depends on their height, move them until the space is balanced
+        for _ in 0..3 {
+            let (first, last) = match (lines.first(), lines.last()) {
+                (Some(first), Some(last)) => (first.y, last.y + last.height),
+                _ => break,
+            };
+            top += match va {
+                VerticalAlignment::Center => (height - last - first) / 2.,
+                _ => height - last,
+            };
+            (lines, overflow) = break_lines(layout, &span, top.max(0.), width, height);
+        }
+    }
+
+    if let (true, Some(overflow)) = (ellipsize, overflow) {
+        lines.retain(|line| line.start < overflow);
+        if let Some(last) = lines.last_mut() {
+            last.layout = ellipsized_line(layout, last.start, last.width, last.height);
+        }
+    }
+
+    let runs = lines
+        .into_iter()
+        .map(|line| Run {
+            layout: line.layout,
+            line: None,
+            x: pos.x + line.x,
+            y: pos.y + line.y,
+        })
+        .collect();
+    (runs, overflow)
+}
+
+fn break_lines(
+    layout: &Layout,
+    span: &impl Fn(f64, f64) -> Option<(f64, f64)>,
+    top: f64,
+    width: f64,
+    height: f64,
+) -> (Vec<Line>, Option<usize>) {
+    let text = layout.text();
+    let spacing = layout.spacing() as f64 / SCALE as f64;
+    let mut line_height = first_line(&line_layout(layout, 0, &text, width)).2;
+    let mut lines = vec![];
+    let mut index = 0;
+    let mut y = top;
+    let mut retried = false;
+    let mut overflow = None;
+    while index < text.len() {
+        let rest = &text[index..];
+        let mut band = None;
+        while y + line_height <= height {
+            match span(y, y + line_height) {
+                Some((left, right)) if right - left >= 1. => {
+                    band = Some((left, right));
+                    break;
+                }
+                _ => y += 1.,
+            }
+        }
+        let (left, right) = band.unwrap_or((0., width));
+        let line = line_layout(layout, index, rest, right - left);
+        let (length, consumed, current_height) = first_line(&line);
+        if band.is_some() && !retried && (current_height - line_height).abs() > 0.5 {
+            line_height = current_height;
+            retried = true;
+            continue;
+        }
+        retried = false;
+        if band.is_none() && overflow.is_none() {
+            overflow = Some(index);
+        }
+        line.set_text(&rest[..length]);
+        lines.push(Line {
+            layout: line,
+            start: index,
+            x: left,
+            y,
+            width: right - left,
+            height: current_height,
+        });
+        y += current_height + spacing;
+        index += consumed.unwrap_or(rest.len()).max(1);
+    }
+    (lines, overflow)
+}
+
+/// Copy of the layout for the text starting at index
+fn line_layout(layout: &Layout, index: usize, text: &str, width: f64) -> Layout {
+    let line = layout.copy();
+    if let Some(attributes) = layout.attributes().and_then(|attributes| attributes.copy()) {
+        attributes.update(0, index as i32, 0);
+        line.set_attributes(Some(&attributes));
+    }
+    if index > 0 && !layout.text()[..index].ends_with('\n') {
+        line.set_indent(0);
+    }
+    line.set_text(text);
+    line.set_width((width * SCALE as f64) as i32);
+    line.set_height(-1);
+    line.set_ellipsize(pango::EllipsizeMode::None);
+    line
+}
+
+/// Text after the byte index that didn't fit, empty if it was shortened with ...
+pub(crate) fn remaining(
+    layout: &Layout,
+    overflow: Option<usize>,
+    ellipsize: bool,
+) -> Option<String> {
+    overflow.map(|index| match ellipsize {
+        true => String::new(),
+        false => layout.text()[index..].to_string(),
+    })
+}
+
+/// Copy of the layout for the text starting at index as one line that ends with ... if it is too long
+fn ellipsized_line(layout: &Layout, index: usize, width: f64, height: f64) -> Layout {
+    let line = line_layout(layout, index, &layout.text()[index..], width);
+    // a positive height keeps only the lines that fit, but at least the first one
+    line.set_height((height * SCALE as f64) as i32);
+    line.set_ellipsize(pango::EllipsizeMode::End);
+    line
+}
+
+/// Length of the first line, bytes up to the second line and height of the first line
+fn first_line(layout: &Layout) -> (usize, Option<usize>, f64) {
+    let consumed = layout
+        .line_readonly(1)
+        .map(|next| next.start_index() as usize);
+    match layout.line_readonly(0) {
+        Some(line) => (
+            line.length() as usize,
+            consumed,
+            line.pixel_extents().1.height() as f64,
+        ),
+        None => (0, consumed, 0.),
+    }
+}
+
+/// Widest horizontal space inside the polygon over the whole band
+fn polygon_span(polygon: &[(f64, f64)], top: f64, bottom: f64) -> Option<(f64, f64)> {
+    let mut heights = vec![top, (top + bottom) / 2., bottom];
+    heights.extend(
+        polygon
+            .iter()
+            .map(|point| point.1)
+            .filter(|&y| y > top && y < bottom),
+    );
+    let mut band: Option<(f64, f64)> = None;
+    for y in heights {
+        let (left, right) = widest_span(polygon, y)?;
+        band = Some(match band {
+            None => (left, right),
+            Some(band) => (band.0.max(left), band.1.min(right)),
+        });
+    }
+    band.filter(|band| band.1 > band.0)
+}
+
+/// Widest space between two edges of the polygon at height y
+fn widest_span(polygon: &[(f64, f64)], y: f64) -> Option<(f64, f64)> {
+    let mut crossings = vec![];
+    for (i, a) in polygon.iter().enumerate() {
+        let b = polygon[(i + 1) % polygon.len()];
+        if (a.1 <= y && b.1 > y) || (b.1 <= y && a.1 > y) {
+            crossings.push(a.0 + (y - a.1) / (b.1 - a.1) * (b.0 - a.0));
+        }
+    }
+    crossings.sort_by(|a, b| a.total_cmp(b));
+    crossings
+        .chunks_exact(2)
+        .map(|pair| (pair[0], pair[1]))
+        .max_by(|a, b| (a.1 - a.0).total_cmp(&(b.1 - b.0)))
+}
+
+/// Box in layout coordinates
+pub(crate) struct Rect {
+    pub(crate) x: f64,
+    pub(crate) y: f64,
+    pub(crate) width: f64,
+    pub(crate) height: f64,
+}
+
+/// Line of the layout with its position inside the layout
+struct ThreadLine {
+    line: LayoutLine,
+    x: f64,
+    top: f64,
+    bottom: f64,
+    baseline: f64,
+}
+
+/// Splits the lines of the layout over the boxes in order
+/// ellipsize: the last line placed ends with ... if there are lines left
+/// Returns the placed lines and the byte index of the first line that didn't fit
+pub(crate) fn thread(
+    layout: &Layout,
+    boxes: &[Rect],
+    va: &VerticalAlignment,
+    ellipsize: bool,
+) -> (Vec<Run>, Option<usize>) {
+    let scale = SCALE as f64;
+    let mut lines = vec![];
+    let mut iter = layout.iter();
+    loop {
+        let (_, logical) = iter.line_extents();
+        let baseline = iter.baseline() as f64 / scale;
+        if let Some(line) = iter.line_readonly() {
+            lines.push(ThreadLine {
+                line,
+                x: logical.x() as f64 / scale,
+                top: logical.y() as f64 / scale,
+                bottom: (logical.y() + logical.height()) as f64 / scale,
+                baseline,
+            });
+        }
+        if !iter.next_line() {
+            break;
+        }
+    }
+
+    let mut runs = vec![];
+    let mut index = 0;
+    let mut last_rect = None;
+    for rect in boxes {
+        let start = index;
+        let top = match lines.get(start) {
+            Some(line) => line.top,
+            None => break,
+        };
+        while index < lines.len() && lines[index].bottom - top <= rect.height {
+            index += 1;
+        }
+        if index == start {
+            continue;
+        }
+        last_rect = Some(rect);
+        let used = lines[index - 1].bottom - top;
+        let offset = match va {
+            VerticalAlignment::Top => 0.,
+            VerticalAlignment::Center => (rect.height - used) / 2.,
+            VerticalAlignment::Bottom => rect.height - used,
+        };
+        for line in &lines[start..index] {
+            runs.push(Run {
+                layout: layout.clone(),
+                line: Some(line.line.clone()),
+                x: rect.x + line.x,
+                y: rect.y + offset + line.baseline - top,
+            });
+        }
+    }
+    let overflow = lines
+        .get(index)
+        .map(|line| line.line.start_index() as usize);
+    if let (true, Some(_), Some(rect), Some(run)) =
+        (ellipsize, overflow, last_rect, runs.last_mut())
+    {
+        let last = &lines[index - 1];
+        let shortened = ellipsized_line(
+            layout,
+            last.line.start_index() as usize,
+            rect.width,
+            last.bottom - last.top,
+        );
+        let x = shortened.iter().line_extents().1.x() as f64 / scale;
+        if let Some(line) = shortened.line_readonly(0) {
+            *run = Run {
+                layout: shortened,
+                line: Some(line),
+                x: rect.x + x,
+                y: run.y,
+            };
+        }
+    }
+    (runs, overflow)
+}
+
+#[cfg(test)]
+mod tests {
+    use cairo_rs::{Context, Format, ImageSurface};
+    use pango::{FontDescription, Layout};
+
+    use super::{flow, thread, Rect};
+    use crate::input::{Pos2, Region, Size2, VerticalAlignment};
+
+    /// Layout of three short lines and the height of one line
+    fn layout() -> (Layout, f64) {
+        let surface = ImageSurface::create(Format::ARgb32, 10, 10).unwrap();
+        let context = Context::new(&surface).unwrap();
+        let layout = pangocairo::create_layout(&context);
+        layout.set_font_description(Some(&FontDescription::from_string("Sans 10")));
+        layout.set_text("a");
+        let height = layout.pixel_extents().1.height() as f64;
+        layout.set_text("a\nb\nc");
+        (layout, height)
+    }
+
+    #[test]
+    fn thread_fills_the_boxes_in_order() {
+        let (layout, height) = layout();
+        let boxes = [
+            Rect {
+                x: 0.,
+                y: 0.,
+                width: 100.,
+                height: height * 2. + 0.5,
+            },
+            Rect {
+                x: 200.,
+                y: 0.,
+                width: 100.,
+                height: height + 0.5,
+            },
+        ];
+        let (runs, overflow) = thread(&layout, &boxes, &VerticalAlignment::Top, false);
+        let xs = runs.iter().map(|run| run.x).collect::<Vec<_>>();
+        assert_eq!(xs, vec![0., 0., 200.]);
+        assert!(runs[0].y < runs[1].y);
+        assert_eq!(overflow, None);
+    }
+
+    #[test]
+    fn thread_skips_small_boxes_and_reports_the_rest() {
+        let (layout, height) = layout();
+        let boxes = [
+            Rect {
+                x: 0.,
+                y: 0.,
+                width: 100.,
+                height: height / 2.,
+            },
+            Rect {
+                x: 200.,
+                y: 0.,
+                width: 100.,
+                height: height * 2. + 0.5,
+            },
+        ];
+        let (runs, overflow) = thread(&layout, &boxes, &VerticalAlignment::Top, false);
+        assert!(runs.iter().all(|run| run.x == 200.));
+        assert_eq!(runs.len(), 2);
+        assert_eq!(overflow, Some(4));
+    }
+
+    #[test]
+    fn flow_continues_lines_below_the_region() {
+        let (layout, height) = layout();
+        let pos = Pos2::new(0., 0.);
+        let size = Size2::new(100., height * 2. + 0.5);
+        let (runs, overflow) = flow(
+            &layout,
+            &Region::Rectangle,
+            &pos,
+            &size,
+            false,
+            &VerticalAlignment::Top,
+            false,
+        );
+        assert_eq!(runs.len(), 3);
+        assert!(runs.windows(2).all(|pair| pair[0].y < pair[1].y));
+        assert_eq!(overflow, Some(4));
+    }
+
+    #[test]
+    fn flow_ellipsizes_the_last_line_inside_the_region() {
+        let (layout, height) = layout();
+        let pos = Pos2::new(0., 0.);
+        let size = Size2::new(100., height * 2. + 0.5);
+        let (runs, overflow) = flow(
+            &layout,
+            &Region::Rectangle,
+            &pos,
+            &size,
+            false,
+            &VerticalAlignment::Top,
+            true,
+        );
+        assert_eq!(runs.len(), 2);
+        assert!(!runs[0].layout.is_ellipsized());
+        assert!(runs[1].layout.is_ellipsized());
+        assert_eq!(overflow, Some(4));
+    }
+
+    #[test]
+    fn flow_places_lines_inside_the_polygon() {
+        let (layout, _) = layout();
+        let points = vec![
+            Pos2::new(20., 0.),
+            Pos2::new(100., 0.),
+            Pos2::new(100., 200.),
+            Pos2::new(20., 200.),
+        ];
+        let (runs, overflow) = flow(
+            &layout,
+            &Region::Polygon(points),
+            &Pos2::new(5., 5.),
+            &Size2::new(100., 100.),
+            false,
+            &VerticalAlignment::Top,
+            false,
+        );
+        assert_eq!(runs.len(), 3);
+        assert!(runs.iter().all(|run| run.x == 25.));
+        assert_eq!(overflow, None);
+    }
+}
@@ -0,0 +1,370 @@
+use cairo_rs::{Context, FillRule, Format, ImageSurface, LineCap, LineJoin, Matrix, SurfaceType};
+use image::RgbaImage;
+use resvg::tiny_skia::{self, PathSegment};
+use resvg::usvg::{self, fontdb, Node, TreeParsing, TreePostProc};
+
+use crate::cairopango::{fitted_pattern, image_surface, paint_group, PainterError};
+use crate::effects::pixels;
+use crate::input::{Graphic, ImageFit};
+use crate::raster::from_image;
+
+impl Graphic {
+    /// Draws the image fitted into the box
+    /// svg: the bytes are an svg document, it is drawn as paths on vector surfaces
+    /// and rasterised with scale pixels per unit otherwise
+    pub(crate) fn draw(
+        &self,
+        svg: bool,
+        context: &Context,
+        scale: f64,
+    ) -> Result<(), PainterError> {
+        let (surface, width, height) = match svg {
+            true => {
+                let tree = self.tree()?;
+                let vector = !matches!(context.target().type_(), SurfaceType::Image);
+                // tiling and what cairo paths can't express, like filters, stay a bitmap
+                if vector && !matches!(self.fit, ImageFit::Tile) && replayable(&tree.root) {
+                    return self.draw_tree(&tree, context);
+                }
+                match self.rasterize(&tree, scale)? {
+                    Some(raster) => raster,
+                    None => return Ok(()),
+                }
+            }
+            false => {
+                let surface = image_surface(&self.bytes)?;
+                let (width, height) = (surface.width() as f64, surface.height() as f64);
+                (surface, width, height)
+            }
+        };
+        let pattern = match fitted_pattern(
+            &surface,
+            width,
+            height,
+            &self.fit,
+            &self.filter,
+            &self.pos,
+            &self.size,
+        ) {
+            Some(pattern) => pattern,
+            None => return Ok(()),
+        };
+        context.save()?;
+        if let Some(transform) = &self.transform {
+            transform.set(context, &self.pos, &self.size);
+        }
+        context.rectangle(self.pos.x, self.pos.y, self.size.width, self.size.height);
+        context.clip();
+        context.set_source(&pattern)?;
+        context.set_operator(self.blend.to_cairo());
+        context.paint_with_alpha(self.opacity)?;
+        context.restore()?;
+        Ok(())
+    }
+
+    /// Parses the svg and converts its text into paths
+    /// Text finds the installed fonts, on linux the ones fontconfig and pango find
+    fn tree(&self) -> Result<usvg::Tree, PainterError> {
+        let mut tree = usvg::Tree::from_data(&self.bytes, &usvg::Options::default())
+            .map_err(PainterError::Svg)?;
+        // loading the fonts is slow, text is converted into paths only when there is some
+        let mut fonts = fontdb::Database::new();
+        if tree.has_text_nodes() {
+            fonts.load_system_fonts();
+        }
+        tree.postprocess(usvg::PostProcessingSteps::default(), &fonts);
+        Ok(tree)
+    }
+
+    /// Draws the svg as cairo paths fitted into the box, so vector output stays vector
+    fn draw_tree(&self, tree: &usvg::Tree, context: &Context) -> Result<(), PainterError> {
+        let (width, height) = (tree.size.width() as f64, tree.size.height() as f64);
+        let (x, y, scale_x, scale_y) = self.fit.place(width, height, &self.pos, &self.size);
+        context.save()?;
+        if let Some(transform) = &self.transform {
+            transform.set(context, &self.pos, &self.size);
+        }
+        context.rectangle(self.pos.x, self.pos.y, self.size.width, self.size.height);
+        context.clip();
+        let grouped = self.blend.needs_group(self.opacity);
+        if grouped {
+            context.push_group();
+        }
+        context.save()?;
+        context.translate(x, y);
+        context.scale(scale_x, scale_y);
+        context.transform(to_matrix(&tree.view_box.to_transform(tree.size)));
+        draw_group(&tree.root, context)?;
+        context.restore()?;
+        if grouped {
+            paint_group(context, self.opacity, &self.blend)?;
+        }
+        context.restore()?;
+        Ok(())
+    }
+
+    /// Renders the svg in the size it gets in the box
+    /// Returns the surface and the size of the svg, None if it is empty
+    fn rasterize(
+        &self,
+        tree: &usvg::Tree,
+        scale: f64,
+    ) -> Result<Option<(ImageSurface, f64, f64)>, PainterError> {
+        let (width, height) = (tree.size.width() as f64, tree.size.height() as f64);
+        let (_, _, scale_x, scale_y) = self.fit.place(width, height, &self.pos, &self.size);
+        let pixel_width = (width * scale_x * scale).ceil();
+        let pixel_height = (height * scale_y * scale).ceil();
+        if !(pixel_width >= 1. && pixel_height >= 1.) {
+            return Ok(None);
+        }
+        let mut pixmap = match tiny_skia::Pixmap::new(pixel_width as u32, pixel_height as u32) {
+            Some(pixmap) => pixmap,
+            None => return Ok(None),
+        };
+        let transform = tiny_skia::Transform::from_scale(
+            (pixel_width / width) as f32,
+            (pixel_height / height) as f32,
+        );
+        resvg::render(tree, transform, &mut pixmap.as_mut());
+
+        // tiny-skia keeps premultiplied rgba, cairo premultiplied argb
+        let image = RgbaImage::from_fn(pixmap.width(), pixmap.height(), |x, y| {
+            let color = pixmap
+                .pixel(x, y)
+                .unwrap_or(tiny_skia::PremultipliedColorU8::TRANSPARENT)
+                .demultiply();
+            image::Rgba([color.red(), color.green(), color.blue(), color.alpha()])
+        });
+        let mut surface =
+            ImageSurface::create(Format::ARgb32, pixel_width as i32, pixel_height as i32)?;
+        let stride = surface.stride() as usize;
+        from_image(&image, &mut pixels(&mut surface)?, stride);
+        Ok(Some((surface, width, height)))
+    }
+}
+
+/// Whether the group only has what draw_group draws: plain groups and paths with solid colors
+fn replayable(group: &usvg::Group) -> bool {
+    group.clip_path.is_none()
+        && group.mask.is_none()
+        && group.filters.is_empty()
+        && group.blend_mode == usvg::BlendMode::Normal
+        && group.children.iter().all(|node| match node {
+            Node::Group(group) => replayable(group),
+            Node::Path(path) => {
+                path.fill
+                    .as_ref()
+                    .is_none_or(|fill| matches!(fill.paint, usvg::Paint::Color(_)))
+                    && path
+                        .stroke
+                        .as_ref()
+                        .is_none_or(|stroke| matches!(stroke.paint, usvg::Paint::Color(_)))
+            }
+            Node::Image(_) => false,
+            Node::Text(text) => text.flattened.as_deref().is_none_or(replayable),
+        })
+}
+
+/// Draws the children of the group with its transform and opacity
+fn draw_group(group: &usvg::Group, context: &Context) -> Result<(), cairo_rs::Error> {
+    context.save()?;
+    context.transform(to_matrix(&group.transform));
+    let opacity = group.opacity.get() as f64;
+    if opacity < 1. {
+        context.push_group();
+    }
+    for node in &group.children {
+        match node {
+            Node::Group(group) => draw_group(group, context)?,
+            Node::Path(path) => draw_path(path, context)?,
+            Node::Image(_) => {}
+            Node::Text(text) => {
+                if let Some(group) = &text.flattened {
+                    draw_group(group, context)?;
+                }
+            }
+        }
+    }
+    if opacity < 1. {
+        context.pop_group_to_source()?;
+        context.paint_with_alpha(opacity)?;
+    }
+    context.restore()
+}
+
+/// Fills and strokes the path in its paint order
+fn draw_path(path: &usvg::Path, context: &Context) -> Result<(), cairo_rs::Error> {
+    if path.visibility != usvg::Visibility::Visible {
+        return Ok(());
+    }
+    match path.paint_order {
+        usvg::PaintOrder::FillAndStroke => {
+            fill_path(path, context)?;
+            stroke_path(path, context)
+        }
+        usvg::PaintOrder::StrokeAndFill => {
+            stroke_path(path, context)?;
+            fill_path(path, context)
+        }
+    }
+}
+
+fn fill_path(path: &usvg::Path, context: &Context) -> Result<(), cairo_rs::Error> {
+    let fill = match &path.fill {
+        Some(fill) => fill,
+        None => return Ok(()),
+    };
+    let color = match &fill.paint {
+        usvg::Paint::Color(color) => color,
+        _ => return Ok(()),
+    };
+    trace(path, context)?;
+    set_color(context, color, fill.opacity.get());
+    context.set_fill_rule(match fill.rule {
+        usvg::FillRule::NonZero => FillRule::Winding,
+        usvg::FillRule::EvenOdd => FillRule::EvenOdd,
+    });
+    context.fill()
+}
+
+fn stroke_path(path: &usvg::Path, context: &Context) -> Result<(), cairo_rs::Error> {
+    let stroke = match &path.stroke {
+        Some(stroke) => stroke,
+        None => return Ok(()),
+    };
+    let color = match &stroke.paint {
+        usvg::Paint::Color(color) => color,
+        _ => return Ok(()),
+    };
+    trace(path, context)?;
+    set_color(context, color, stroke.opacity.get());
+    context.set_line_width(stroke.width.get() as f64);
+    context.set_miter_limit(stroke.miterlimit.get() as f64);
+    context.set_line_cap(match stroke.linecap {
+        usvg::LineCap::Butt => LineCap::Butt,
+        usvg::LineCap::Round => LineCap::Round,
+        usvg::LineCap::Square => LineCap::Square,
+    });
+    context.set_line_join(match stroke.linejoin {
+        usvg::LineJoin::Miter | usvg::LineJoin::MiterClip => LineJoin::Miter,
+        usvg::LineJoin::Round => LineJoin::Round,
+        usvg::LineJoin::Bevel => LineJoin::Bevel,
+    });
+    let dash = stroke
+        .dasharray
+        .as_ref()
+        .map(|dash| dash.iter().map(|&d| d as f64).collect::<Vec<_>>())
+        .unwrap_or_default();
+    context.set_dash(&dash, stroke.dashoffset as f64);
+    context.stroke()
+}
+
+fn set_color(context: &Context, color: &usvg::Color, opacity: f32) {
+    context.set_source_rgba(
+        color.red as f64 / 255.,
+        color.green as f64 / 255.,
+        color.blue as f64 / 255.,
+        opacity as f64,
+    );
+}
+
+/// Replaces the path of the context with the svg path
+fn trace(path: &usvg::Path, context: &Context) -> Result<(), cairo_rs::Error> {
+    context.new_path();
+    for segment in path.data.segments() {
+        match segment {
+            PathSegment::MoveTo(p) => context.move_to(p.x as f64, p.y as f64),
+            PathSegment::LineTo(p) => context.line_to(p.x as f64, p.y as f64),
+            PathSegment::QuadTo(c, p) => {
+                // a quadratic curve as cubic one with the control points 2/3 of the way to c
+                let (x0, y0) = context.current_point()?;
+                let (cx, cy) = (c.x as f64, c.y as f64);
+                let (x, y) = (p.x as f64, p.y as f64);
+                context.curve_to(
+                    x0 + (cx - x0) * 2. / 3.,
+                    y0 + (cy - y0) * 2. / 3.,
+                    x + (cx - x) * 2. / 3.,
+                    y + (cy - y) * 2. / 3.,
+                    x,
+                    y,
+                );
+            }
+            PathSegment::CubicTo(c1, c2, p) => context.curve_to(
+                c1.x as f64,
+                c1.y as f64,
+                c2.x as f64,
+                c2.y as f64,
+                p.x as f64,
+                p.y as f64,
+            ),
+            PathSegment::Close => context.close_path(),
+        }
+    }
+    Ok(())
+}
+
+/// Matrix of cairo from a transform of tiny-skia
+fn to_matrix(transform: &tiny_skia::Transform) -> Matrix {
+    Matrix::new(
+        transform.sx as f64,
+        transform.ky as f64,
+        transform.kx as f64,
+        transform.sy as f64,
+        transform.tx as f64,
+        transform.ty as f64,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use cairo_rs::{Content, Context, RecordingSurface};
+
+    use super::replayable;
+    use crate::input::{Graphic, ImageFit, Pos2, Size2};
+
+    fn graphic(svg: &str) -> Graphic {
+        Graphic {
+            bytes: svg.as_bytes().to_vec(),
+            pos: Pos2::new(10., 20.),
+            size: Size2::new(100., 50.),
+            fit: ImageFit::Stretch,
+            filter: Default::default(),
+            transform: None,
+            opacity: 1.,
+            blend: Default::default(),
+            layer: 0,
+            z_index: 0,
+        }
+    }
+
+    #[test]
+    fn solid_paths_are_drawn_as_paths() {
+        let svg = graphic(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="20" height="10">
+                <rect x="10" y="0" width="10" height="10" fill="red"/>
+            </svg>"#,
+        );
+        let tree = svg.tree().ok().unwrap();
+        assert!(replayable(&tree.root));
+        let surface = RecordingSurface::create(Content::ColorAlpha, None).unwrap();
+        let context = Context::new(&surface).unwrap();
+        svg.draw(true, &context, 1.).ok().unwrap();
+        // the right half of the svg stretched into the box
+        let (x, y, width, height) = surface.ink_extents();
+        assert_eq!((x, y, width, height), (60., 20., 50., 50.));
+    }
+
+    #[test]
+    fn gradients_are_rasterised() {
+        let svg = graphic(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="20" height="10">
+                <linearGradient id="g">
+                    <stop offset="0" stop-color="red"/>
+                    <stop offset="1" stop-color="blue"/>
+                </linearGradient>
+                <rect width="20" height="10" fill="url(#g)"/>
+            </svg>"#,
+        );
+        assert!(!replayable(&svg.tree().ok().unwrap().root));
+    }
+}
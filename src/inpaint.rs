@@ -0,0 +1,243 @@
+use cairo_rs::{Context, Format, ImageSurface};
+use image::{ImageError, RgbaImage};
+
+use crate::cairopango::PainterError;
+use crate::effects::pixels;
+use crate::input::{InpaintMask, Pos2, Size2};
+use crate::raster::from_image;
+
+/// Smoothing passes over the filled pixels
+const DIFFUSION_STEPS: usize = 50;
+
+/// Area of the image in pixels: left, top, width, height
+type Area = (u32, u32, u32, u32);
+
+impl InpaintMask {
+    /// Inpaints the masked pixels of the box in the background image and draws the result
+    /// Fails if the bitmap of the mask can't be decoded
+    pub(crate) fn clean(
+        &self,
+        image: &mut RgbaImage,
+        pos: &Pos2,
+        size: &Size2,
+        context: &Context,
+    ) -> Result<(), PainterError> {
+        let (image_width, image_height) = image.dimensions();
+        let left = pos.x.floor().clamp(0., image_width as f64) as u32;
+        let top = pos.y.floor().clamp(0., image_height as f64) as u32;
+        let right = (pos.x + size.width).ceil().clamp(0., image_width as f64) as u32;
+        let bottom = (pos.y + size.height).ceil().clamp(0., image_height as f64) as u32;
+        if right <= left || bottom <= top {
+            return Ok(());
+        }
+        let area = (left, top, right - left, bottom - top);
+        let mask = self.mask(image, area)?;
+        inpaint(image, area, &mask);
+
+        let patch = image::imageops::crop_imm(image, area.0, area.1, area.2, area.3).to_image();
+        let mut surface = ImageSurface::create(Format::ARgb32, area.2 as i32, area.3 as i32)?;
+        let stride = surface.stride() as usize;
+        from_image(&patch, &mut pixels(&mut surface)?, stride);
+        context.save()?;
+        context.identity_matrix();
+        context.set_source_surface(&surface, left as f64, top as f64)?;
+        context.paint()?;
+        context.restore()?;
+        Ok(())
+    }
+
+    /// Pixels of the area that are filled, row by row
+    fn mask(&self, image: &RgbaImage, area: Area) -> Result<Vec<bool>, ImageError> {
+        let (left, top, width, height) = area;
+        match self {
+            InpaintMask::Bitmap(bytes) => {
+                let bitmap = image::load_from_memory(bytes)?.to_luma_alpha8();
+                let (mask_width, mask_height) = bitmap.dimensions();
+                let mut mask = Vec::with_capacity((width * height) as usize);
+                for y in 0..height {
+                    for x in 0..width {
+                        let mx = (x as u64 * mask_width as u64 / width as u64) as u32;
+                        let my = (y as u64 * mask_height as u64 / height as u64) as u32;
+                        let [luma, alpha] = bitmap.get_pixel(mx, my).0;
+                        mask.push(luma > 127 && alpha > 127);
+                    }
+                }
+                Ok(mask)
+            }
+            InpaintMask::Dark { threshold, dilate } => {
+                let dark = (0..height)
+                    .flat_map(|y| (0..width).map(move |x| (x, y)))
+                    .map(|(x, y)| {
+                        let [r, g, b, _] = image.get_pixel(left + x, top + y).0;
+                        let luma = 0.2126 * r as f64 + 0.7152 * g as f64 + 0.0722 * b as f64;
+                        luma < *threshold as f64
+                    })
+                    .collect::<Vec<_>>();
+                let d = *dilate as i64;
+                let mut mask = vec![false; dark.len()];
+                for y in 0..height as i64 {
+                    for x in 0..width as i64 {
+                        if !dark[(y * width as i64 + x) as usize] {
+                            continue;
+                        }
+                        for ny in (y - d).max(0)..=(y + d).min(height as i64 - 1) {
+                            for nx in (x - d).max(0)..=(x + d).min(width as i64 - 1) {
+                                mask[(ny * width as i64 + nx) as usize] = true;
+                            }
+                        }
+                    }
+                }
+                Ok(mask)
+            }
+        }
+    }
+}
+
+/// Fills the masked pixels of the area from the pixels around them
+/// The holes are filled from their border inwards and then smoothed by diffusion
+fn inpaint(image: &mut RgbaImage, area: Area, mask: &[bool]) {
+    let (left, top, width, height) = area;
+    let (image_width, image_height) = image.dimensions();
+    let mut known = mask.iter().map(|masked| !masked).collect::<Vec<_>>();
+    let index = |x: i64, y: i64| {
+        let (ax, ay) = (x - left as i64, y - top as i64);
+        (ax >= 0 && ay >= 0 && ax < width as i64 && ay < height as i64)
+            .then(|| (ay * width as i64 + ax) as usize)
+    };
+    let inside =
+        |x: i64, y: i64| x >= 0 && y >= 0 && x < image_width as i64 && y < image_height as i64;
+    let holes = (0..height)
+        .flat_map(|y| (0..width).map(move |x| (x, y)))
+        .filter(|&(x, y)| mask[(y * width + x) as usize])
+        .map(|(x, y)| ((left + x) as i64, (top + y) as i64))
+        .collect::<Vec<_>>();
+
+    let mut remaining = holes.clone();
+    while !remaining.is_empty() {
+        let mut filled = vec![];
+        for &(x, y) in &remaining {
+            let mut sum = [0.; 4];
+            let mut count = 0.;
+            for (dx, dy) in [
+                (-1, -1),
+                (0, -1),
+                (1, -1),
+                (-1, 0),
+                (1, 0),
+                (-1, 1),
+                (0, 1),
+                (1, 1),
+            ] {
+                let (nx, ny) = (x + dx, y + dy);
+                let is_known = match index(nx, ny) {
+                    Some(i) => known[i],
+                    None => inside(nx, ny),
+                };
+                if is_known {
+                    for (s, c) in sum.iter_mut().zip(image.get_pixel(nx as u32, ny as u32).0) {
+                        *s += c as f64;
+                    }
+                    count += 1.;
+                }
+            }
+            if count > 0. {
+                filled.push((x, y, sum.map(|s| (s / count).round() as u8)));
+            }
+        }
+        if filled.is_empty() {
+            break;
+        }
+        for &(x, y, color) in &filled {
+            image.put_pixel(x as u32, y as u32, image::Rgba(color));
+            if let Some(i) = index(x, y) {
+                known[i] = true;
+            }
+        }
+        remaining.retain(|&(x, y)| index(x, y).is_some_and(|i| !known[i]));
+    }
+
+    for _ in 0..DIFFUSION_STEPS {
+        for &(x, y) in &holes {
+            let mut sum = [0.; 4];
+            let mut count = 0.;
+            for (dx, dy) in [(0, -1), (-1, 0), (1, 0), (0, 1)] {
+                let (nx, ny) = (x + dx, y + dy);
+                if inside(nx, ny) {
+                    for (s, c) in sum.iter_mut().zip(image.get_pixel(nx as u32, ny as u32).0) {
+                        *s += c as f64;
+                    }
+                    count += 1.;
+                }
+            }
+            image.put_pixel(
+                x as u32,
+                y as u32,
+                image::Rgba(sum.map(|s| (s / count).round() as u8)),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cairo_rs::{Context, Format, ImageSurface};
+    use image::{Rgba, RgbaImage};
+
+    use super::inpaint;
+    use crate::cairopango::PainterError;
+    use crate::input::{InpaintMask, Pos2, Size2};
+
+    #[test]
+    fn hole_is_filled_from_its_border() {
+        // horizontal gradient with a black square in the middle
+        let mut image = RgbaImage::from_fn(12, 12, |x, y| {
+            match (4..8).contains(&x) && (4..8).contains(&y) {
+                true => Rgba([0, 0, 0, 255]),
+                false => Rgba([(x * 20) as u8, 100, 100, 255]),
+            }
+        });
+        let mask = (0..12 * 12)
+            .map(|i| (4..8).contains(&(i % 12)) && (4..8).contains(&(i / 12)))
+            .collect::<Vec<_>>();
+        inpaint(&mut image, (0, 0, 12, 12), &mask);
+        for y in 4..8 {
+            for x in 4..8 {
+                let [r, g, b, a] = image.get_pixel(x, y).0;
+                assert!((60..=160).contains(&r), "{} {} {}", x, y, r);
+                assert_eq!((g, b, a), (100, 100, 255));
+            }
+        }
+        assert!(image.get_pixel(4, 6).0[0] < image.get_pixel(7, 6).0[0]);
+        assert_eq!(image.get_pixel(0, 0).0, [0, 100, 100, 255]);
+    }
+
+    #[test]
+    fn dark_mask_is_dilated() {
+        let image = RgbaImage::from_fn(5, 5, |x, y| match (x, y) {
+            (2, 2) => Rgba([0, 0, 0, 255]),
+            _ => Rgba([255, 255, 255, 255]),
+        });
+        let mask = InpaintMask::Dark {
+            threshold: 50,
+            dilate: 1,
+        }
+        .mask(&image, (0, 0, 5, 5))
+        .unwrap();
+        assert_eq!(mask.iter().filter(|&&masked| masked).count(), 9);
+        assert!(!mask[0] && mask[6] && mask[12] && mask[18] && !mask[24]);
+    }
+
+    #[test]
+    fn undecodable_bitmap_is_an_error() {
+        let mut image = RgbaImage::new(4, 4);
+        let surface = ImageSurface::create(Format::ARgb32, 4, 4).unwrap();
+        let context = Context::new(&surface).unwrap();
+        let result = InpaintMask::Bitmap(vec![1, 2, 3]).clean(
+            &mut image,
+            &Pos2::new(0., 0.),
+            &Size2::new(4., 4.),
+            &context,
+        );
+        assert!(matches!(result, Err(PainterError::Image(_))));
+    }
+}
@@ -1,14 +1,135 @@
 use image::codecs::pnm::PnmSubtype;
 /// Main Item that contains the data
 pub struct Data {
-    /// Text block
-    pub items: Vec<Text>,
+    /// Text blocks and graphics, drawn in list order
+    pub items: Vec<Item>,
     /// Style to fallback to when nothing is specified in Text
     pub global_style: Styling,
     /// The alignment of the text within the box when not specified in Text
     pub global_align: Alignments,
-    /// The background image as png in bytes
-    pub background: Option<Vec<u8>>,
+    /// The background image fitted into the canvas
+    pub background: Option<BackgroundImage>,
+    /// Layers the items are drawn in, a missing layer is visible and opaque
+    pub layers: Vec<Layer>,
+}
+
+/// Group of items that is drawn together
+/// Layers are drawn in order of their index, above the background image
+pub struct Layer {
+    /// name of the layer, also of its stack in the OpenRaster export
+    pub name: String,
+    /// items of hidden layers are not drawn or annotated
+    /// The OpenRaster export keeps them in a hidden stack
+    pub visible: bool,
+    /// from 0 transparent to 1 opaque, applied to the whole layer
+    pub opacity: f64,
+    /// how the whole layer is combined with the layers below it
+    pub blend: BlendMode,
+}
+
+/// Element of the scene
+pub enum Item {
+    Text(Text),
+    /// raster image like a logo or a watermark, bytes in any format the image crate decodes
+    Image(Graphic),
+    /// vector graphic, bytes of an svg document
+    /// pdf, svg and ps output draw it as paths, png output rasterises it with resvg
+    /// With gradients, patterns, embedded images, clip paths, masks, filters or ImageFit::Tile
+    /// it is rasterised in pdf, svg and ps output too, at 300 dpi
+    Svg(Graphic),
+    /// vector shape like a panel border, an arrow or a speech bubble
+    Shape(Shape),
+}
+
+/// Shape drawn with cairo paths, vector output stays vector
+pub struct Shape {
+    /// outline of the shape
+    pub kind: ShapeKind,
+    /// upper left corner of the box, points of the shape are relative to it
+    pub pos: Pos2,
+    /// width and height of the box
+    pub size: Size2,
+    /// fill of the shape, None draws only the outline
+    pub fill: Option<Paint>,
+    /// outline drawn over the fill
+    pub stroke: Option<Stroke>,
+    /// Rotation, skew and matrix of the box
+    pub transform: Option<Transform>,
+    /// from 0 transparent to 1 opaque, fill and outline together
+    pub opacity: f64,
+    /// how the shape is combined with what is below it
+    pub blend: BlendMode,
+    /// index of the layer in Data::layers
+    pub layer: usize,
+    /// items of a layer with a higher z-index are drawn above, equal ones in list order
+    pub z_index: i32,
+}
+
+/// Outline of a shape, points are relative to the upper left corner of the box
+pub enum ShapeKind {
+    /// the box with corners rounded by the radius in px
+    Rectangle { radius: f64 },
+    /// ellipse inside the box
+    Ellipse,
+    /// straight line, drawn only by the stroke
+    Line { from: Pos2, to: Pos2 },
+    /// closed polygon through the points
+    Polygon(Vec<Pos2>),
+    /// path data like d in svg
+    Path(String),
+    /// ellipse inside the box with a tail whose tip is at the point
+    Bubble {
+        /// point the tail points at
+        tip: Pos2,
+        /// angle of the ellipse in degrees the tail starts from
+        spread: f64,
+    },
+}
+
+/// Image placed in a box
+pub struct Graphic {
+    /// bytes of the file
+    pub bytes: Vec<u8>,
+    /// upper left corner of the box
+    pub pos: Pos2,
+    /// width and height of the box
+    pub size: Size2,
+    /// how the image is scaled into the box
+    pub fit: ImageFit,
+    /// resampling when the image is scaled
+    pub filter: ImageFilter,
+    /// Rotation, skew and matrix of the box
+    pub transform: Option<Transform>,
+    /// from 0 transparent to 1 opaque
+    pub opacity: f64,
+    /// how the image is combined with what is below it
+    pub blend: BlendMode,
+    /// index of the layer in Data::layers
+    pub layer: usize,
+    /// items of a layer with a higher z-index are drawn above, equal ones in list order
+    pub z_index: i32,
+}
+
+#[derive(Default)]
+/// Blend mode of a drawing with what is below it, like mix-blend-mode in css
+pub enum BlendMode {
+    #[default]
+    Normal,
+    Multiply,
+    Screen,
+    Overlay,
+    Darken,
+    Lighten,
+    ColorDodge,
+    ColorBurn,
+    HardLight,
+    SoftLight,
+    Difference,
+    Exclusion,
+    Hue,
+    Saturation,
+    Color,
+    Luminosity,
 }
 
 /// Alignment of the text within the box
@@ -33,30 +154,394 @@ pub struct Text {
     pub size: Size2,
     /// fontsize
     pub font_size: f64,
-    /// fontcolor
-    pub font_color: Rgb,
+    /// fill of the text
+    pub font_color: Paint,
     /// Background of text section
     pub background: Background,
     /// Alternative style for text section
     pub style: Option<Styling>,
     /// Alternative alignment for text section
     pub align: Option<Alignments>,
-    /// the color of the outline
-    pub outline_color: Rgba,
-    /// The thickness of the outline/stroke/shadow...
-    pub font_stroke: f64,
+    /// Outlines drawn under the fill, the first one is the outermost
+    pub strokes: Vec<Stroke>,
+    /// Shadows and glows drawn under the outlines
+    pub effects: Vec<Effect>,
+    /// Order of outlines and fill
+    pub paint_order: PaintOrder,
+    /// Rotation, skew and matrix of the textblock and its background
+    pub transform: Option<Transform>,
+    /// Curve the text follows in a single line instead of filling the box
+    pub path: Option<TextPath>,
+    /// Shape inside the box the lines are fitted into
+    pub region: Region,
+    /// Boxes the lines continue in when the textblock is full
+    /// Lines keep the width of the textblock, region is ignored
+    pub frames: Vec<Frame>,
+    /// What happens when the text doesn't fit into the textblock
+    pub overflow: OverflowPolicy,
+    /// Padding, border, corners and opacity of the box and its frames
+    pub decoration: BoxStyle,
+    /// Corners the textblock is projected onto: top left, top right, bottom right, bottom left
+    /// Raster output is resampled, vector output gets projected glyph outlines
+    pub quad: Option<[Pos2; 4]>,
+    /// index of the layer in Data::layers
+    pub layer: usize,
+    /// items of a layer with a higher z-index are drawn above, equal ones in list order
+    /// The background of the textblock is drawn right below its text
+    pub z_index: i32,
+    /// from 0 transparent to 1 opaque, applied to the textblock with its background
+    pub opacity: f64,
+    /// how the textblock with its background is combined with what is below it
+    pub blend: BlendMode,
+}
+
+#[derive(Default)]
+/// Handling of text that doesn't fit into its textblock
+/// Every overflowing item is reported by Painter::overflows
+pub enum OverflowPolicy {
+    /// Text is drawn outside of the box
+    #[default]
+    Visible,
+    /// Text is cut off at the box
+    Clip,
+    /// Text is shortened with ... at the end
+    /// Text along a path is never cut, so it is not shortened either
+    Ellipsize,
+    /// Fontsize is reduced until the text fits
+    Shrink,
+    /// Data::painter fails
+    Error,
+}
+
+/// Box linked to a textblock
+pub struct Frame {
+    /// upper left corner of the box
+    pub pos: Pos2,
+    /// width and height of the box
+    pub size: Size2,
+}
+
+#[derive(Default)]
+/// Shape text is flowed into, every line gets the width of the shape at its height
+pub enum Region {
+    /// the whole box
+    #[default]
+    Rectangle,
+    /// ellipse touching the sides of the box
+    Ellipse,
+    /// Polygon with points relative to the upper left corner of the textblock
+    Polygon(Vec<Pos2>),
+}
+
+/// Text along a curve
+pub struct TextPath {
+    /// the curve
+    pub curve: Curve,
+    /// distance along the curve where the text starts in px
+    pub start_offset: f64,
+    /// alignment of the text along the curve
+    pub align: HorizontalAlignment,
+    /// side of the curve the text is on
+    pub side: PathSide,
+}
+
+/// Curve for text, points are relative to the upper left corner of the textblock
+pub enum Curve {
+    /// Clockwise arc, counterclockwise if end_angle is smaller than start_angle
+    Arc {
+        /// center of the circle
+        center: Pos2,
+        /// radius of the circle in px
+        radius: f64,
+        /// start in degrees, 0 is right, 90 is down
+        start_angle: f64,
+        /// end in degrees
+        end_angle: f64,
+    },
+    /// Clockwise circle
+    Circle {
+        /// center of the circle
+        center: Pos2,
+        /// radius of the circle in px
+        radius: f64,
+        /// start in degrees, 0 is right, 90 is down
+        start_angle: f64,
+    },
+    /// Path data like in svg, e.g. "M 0 50 Q 50 0 100 50"
+    Svg(String),
+}
+
+#[derive(Default)]
+/// Side of the curve, like side in svg textPath
+pub enum PathSide {
+    /// The text stands on the curve in its direction
+    #[default]
+    Left,
+    /// The curve is reversed, the text is on the other side
+    Right,
+}
+
+/// Transformation of a textblock around an anchor
+pub struct Transform {
+    /// clockwise rotation in degrees
+    pub rotation: f64,
+    /// horizontal skew in degrees
+    pub skew_x: f64,
+    /// vertical skew in degrees
+    pub skew_y: f64,
+    /// Affine matrix [xx, yx, xy, yy, x0, y0] applied after rotation and skew
+    pub matrix: Option<[f64; 6]>,
+    /// point the transformation is applied around
+    pub anchor: Anchor,
+}
+
+#[derive(Default)]
+/// Point of a textblock
+pub enum Anchor {
+    /// center of the textblock
+    #[default]
+    Center,
+    /// upper left corner
+    TopLeft,
+    /// upper right corner
+    TopRight,
+    /// lower left corner
+    BottomLeft,
+    /// lower right corner
+    BottomRight,
+    /// point relative to the upper left corner
+    Custom(Pos2),
+}
+
+#[derive(Default)]
+/// Order in which fill and outlines are drawn, like paint-order in svg
+pub enum PaintOrder {
+    /// Outlines are drawn under the fill
+    #[default]
+    StrokeUnder,
+    /// Outlines are drawn over the fill
+    StrokeOver,
+    /// Only the outlines are drawn
+    StrokeOnly,
+    /// Only the fill is drawn
+    FillOnly,
+}
+
+/// Blurred effect behind the text
+/// Rasterised, vector outputs get an embedded image
+pub enum Effect {
+    /// Shadow moved away from the text
+    DropShadow {
+        /// offset of the shadow on the page in px
+        offset: Pos2,
+        /// color of the shadow
+        color: Rgba,
+        /// standard deviation of the gaussian blur in px
+        blur: f64,
+        /// how far the shadow grows beyond the outline in px
+        spread: f64,
+    },
+    /// Glow around the text
+    Glow {
+        /// color of the glow
+        color: Rgba,
+        /// standard deviation of the gaussian blur in px
+        blur: f64,
+        /// how far the glow grows beyond the outline in px
+        spread: f64,
+    },
+}
+
+/// Outline around the text
+pub struct Stroke {
+    /// thickness of the outline
+    pub width: f64,
+    /// paint of the outline
+    pub paint: Paint,
+    /// how corners are drawn
+    pub join: StrokeJoin,
+    /// how ends of open lines are drawn
+    pub cap: StrokeCap,
+    /// Limit of the miter length in multiples of the width, only used by StrokeJoin::Miter
+    pub miter_limit: f64,
+    /// Lengths of alternating dashes and gaps, empty for a solid line
+    pub dash: Vec<f64>,
+    /// offset into the dash pattern
+    pub dash_offset: f64,
 }
 
 /// background of text box
 pub enum Background {
-    /// bytes of image
-    Bytes(Vec<u8>),
+    /// image fitted into the box
+    Image(BackgroundImage),
     /// image color in rgb format from 0 to 255
     Rgb(Rgb),
+    /// Removes the original lettering from Data::background by filling the mask from the pixels around it
+    /// Works on the pixels under pos and size, transform and quad are not applied
+    Inpaint(InpaintMask),
     /// Do nothing
     None,
 }
 
+/// Decoration of a box, the background fills its shape
+pub struct BoxStyle {
+    /// space between the box and the text
+    pub padding: Padding,
+    /// line along the shape of the box
+    pub border: Option<Stroke>,
+    /// radius of the corners in px for BoxShape::Rectangle
+    pub radius: f64,
+    /// opacity of the background from 0 to 1
+    pub opacity: f64,
+    /// shape of the background, use Region::Ellipse to fit the text into an ellipse
+    pub shape: BoxShape,
+}
+
+#[derive(Default)]
+/// Space between the box and the text in px
+pub struct Padding {
+    pub top: f64,
+    pub right: f64,
+    pub bottom: f64,
+    pub left: f64,
+}
+
+#[derive(Default)]
+/// Shape of a box
+pub enum BoxShape {
+    /// rectangle with rounded corners
+    #[default]
+    Rectangle,
+    /// ellipse touching the sides of the box
+    Ellipse,
+    /// rectangle with half circles at the short sides
+    Pill,
+}
+
+/// Image that fills a box
+pub struct BackgroundImage {
+    /// bytes of an image in any format the image crate decodes
+    pub bytes: Vec<u8>,
+    /// how the image is scaled into the box
+    pub fit: ImageFit,
+    /// resampling when the image is scaled
+    pub filter: ImageFilter,
+}
+
+#[derive(Default)]
+/// Scaling of an image into a box
+pub enum ImageFit {
+    /// scaled to the size of the box, the aspect ratio changes
+    #[default]
+    Stretch,
+    /// largest size that shows the whole image, centered
+    Contain,
+    /// smallest size that covers the box, centered and cut off
+    Cover,
+    /// repeated in its own size from the upper left corner
+    Tile,
+    /// own size, centered
+    Center,
+}
+
+#[derive(Default)]
+/// Resampling filter for scaled images
+pub enum ImageFilter {
+    /// fast filter with quality like Nearest
+    Fast,
+    /// reasonable quality
+    #[default]
+    Good,
+    /// highest quality, can be slow
+    Best,
+    /// nearest neighbour
+    Nearest,
+    /// linear interpolation of the neighbours
+    Bilinear,
+}
+
+/// Pixels of the background image that are inpainted
+pub enum InpaintMask {
+    /// bytes of an image that is stretched over the textblock, white pixels are filled
+    Bitmap(Vec<u8>),
+    /// pixels darker than threshold, 0-255, grown by dilate px
+    Dark { threshold: u8, dilate: u32 },
+}
+
+#[derive(Default)]
+/// Corners of an outline
+pub enum StrokeJoin {
+    /// Sharp corner, cut off at the miter limit
+    Miter,
+    /// Rounded corner
+    #[default]
+    Round,
+    /// Corner cut off at half the width
+    Bevel,
+}
+
+#[derive(Default)]
+/// Ends of an outline
+pub enum StrokeCap {
+    /// Ends exactly at the end point
+    #[default]
+    Butt,
+    /// Rounded end around the end point
+    Round,
+    /// Squared end around the end point
+    Square,
+}
+
+/// Paint used to fill text
+pub enum Paint {
+    /// Picked from the background under the textblock, see Painter::chosen_colors
+    /// contrast: smallest WCAG contrast ratio from 1 to 21, 4.5 for normal text
+    /// A fill contrasts with the background, an outline with the fill
+    Auto { contrast: f64 },
+    /// Single color with alpha chanel
+    Solid(Rgba),
+    /// Gradient along a line
+    LinearGradient(LinearGradient),
+    /// Gradient between two circles
+    RadialGradient(RadialGradient),
+    /// Image in bytes in any format the image crate decodes, repeated over the textblock
+    Image(Vec<u8>),
+}
+
+/// Gradient from start to end
+/// Points are relative to the upper left corner of the textblock
+pub struct LinearGradient {
+    /// where the gradient begins
+    pub start: Pos2,
+    /// where the gradient ends
+    pub end: Pos2,
+    /// colors along the gradient
+    pub stops: Vec<ColorStop>,
+}
+
+/// Gradient from the inner to the outer circle
+/// Points are relative to the upper left corner of the textblock
+pub struct RadialGradient {
+    /// center of the inner circle
+    pub inner_center: Pos2,
+    /// radius of the inner circle
+    pub inner_radius: f64,
+    /// center of the outer circle
+    pub outer_center: Pos2,
+    /// radius of the outer circle
+    pub outer_radius: f64,
+    /// colors along the gradient
+    pub stops: Vec<ColorStop>,
+}
+
+/// Color at a position of a gradient
+pub struct ColorStop {
+    /// position on the gradient from 0 to 1
+    pub offset: f64,
+    /// color at the position
+    pub color: Rgba,
+}
+
 /// Text input type
 pub enum Mode {
     /// Plain
@@ -216,6 +701,9 @@ pub enum OutputMode {
     Qoi,
     /// An image in WebP Format.
     WebP,
+    /// Layered OpenRaster zip for Krita and GIMP
+    /// The background image, every box, text, image and shape are separate layers
+    OpenRaster,
 }
 
 /// font configuration
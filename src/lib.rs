@@ -1,14 +1,29 @@
 #![allow(dead_code)]
+pub mod annotation;
+pub mod augment;
 pub mod cairopango;
+mod contrast;
+pub mod detect;
+mod effects;
+mod flow;
+mod graphic;
+mod inpaint;
 pub mod input;
 pub mod merge_pdf;
+pub mod metrics;
+mod ora;
+mod path;
+mod perspective;
+mod raster;
 pub mod save;
+mod shape;
+pub mod synth;
 
 #[cfg(test)]
 mod testing {
     use crate::input::{
-        Alignments, Background, Data, Font, HorizontalAlignment, Mode, OutputMode, Pos2,
-        ReadDirection, Rgb, Rgba, Size2, Styling, Text, VerticalAlignment, Wrap,
+        Alignments, Background, Data, Font, HorizontalAlignment, Item, Mode, OutputMode, Paint,
+        Pos2, ReadDirection, Rgb, Rgba, Size2, Stroke, Styling, Text, VerticalAlignment, Wrap,
     };
     use crate::save::{output, OutputError};
     use std::fs::File;
@@ -21,15 +36,27 @@ mod testing {
             pos: Pos2::new(50.0, 50.0),
             size: Size2::new(100.0, 200.0),
             font_size: 12.0,
-            font_color: Rgb::new(0.0, 255.0, 0.0),
-            outline_color: Rgba::new(0.0, 0.0, 1.0, 0.5),
-            font_stroke: 5.0,
+            font_color: Paint::Solid(Rgba::new(0.0, 255.0, 0.0, 1.0)),
+            strokes: vec![Stroke::new(5.0, Paint::Solid(Rgba::new(0.0, 0.0, 1.0, 0.5)))],
+            effects: vec![],
+            paint_order: Default::default(),
+            transform: None,
+            path: None,
+            region: Default::default(),
+            frames: vec![],
+            overflow: Default::default(),
+            decoration: Default::default(),
+            quad: None,
+            layer: 0,
+            z_index: 0,
+            opacity: 1.,
+            blend: Default::default(),
             background: Background::Rgb(Rgb::new(1.0, 0.0, 0.0)),
             style: None,
             align: None,
         };
         let data = Data {
-            items: vec![text],
+            items: vec![Item::Text(text)],
             global_style: Styling {
                 spacing: None,
                 line_spacing: None,
@@ -55,10 +82,13 @@ mod testing {
                 va: VerticalAlignment::Top,
             },
             background: None,
+            layers: vec![],
         };
-        let painter = data.painter(OutputMode::Svg, 1518., 2150.).unwrap();
+        let painter = data
+            .painter(&OutputMode::Svg, Some(&Size2::new(1518., 2150.)))
+            .unwrap();
         let mut file = File::create("test.svg").unwrap();
-        match output(OutputMode::Pdf(false), "".into(), painter) {
+        match output(OutputMode::Pdf(false), "".into(), painter, false) {
             Ok(v) => {
                 //TODO: add to output
             }
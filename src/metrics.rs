@@ -0,0 +1,261 @@
+use cairo_rs::{Context, Format, ImageSurface};
+use pango::SCALE;
+use pangocairo::create_context;
+
+use crate::input::{Alignments, Pos2, Styling, Text};
+
+/// Size of a textblock as it would be drawn
+pub struct TextMetrics {
+    /// Extents of the drawn glyphs, relative to the upper left corner of the textblock
+    pub ink: Extents,
+    /// Extents of the text including line height, relative to the upper left corner of the textblock
+    pub logical: Extents,
+    /// number of lines
+    pub line_count: usize,
+    /// every line from top to bottom
+    pub lines: Vec<LineMetrics>,
+    /// if text was shortened with ...
+    pub ellipsized: bool,
+    /// if the text is larger than the textblock
+    pub overflowed: bool,
+    /// fontsize the text is drawn with
+    pub font_size: f64,
+}
+
+/// Rectangle in px
+pub struct Extents {
+    /// left side
+    pub x: f64,
+    /// top side
+    pub y: f64,
+    /// width in px
+    pub width: f64,
+    /// height in px
+    pub height: f64,
+}
+
+/// Size of a single line, along the reading direction for vertical text
+pub struct LineMetrics {
+    /// distance of the baseline from the top of the text
+    pub baseline: f64,
+    /// width of the line
+    pub width: f64,
+}
+
+/// Measures the text without drawing it
+/// The text is laid out exactly like in Data::painter, including the overflow policy,
+/// the region it flows into and the frames it continues in
+/// align: used when the text has no alignment, like Data::global_align in Data::painter
+pub fn measure(
+    text: &Text,
+    style: &Styling,
+    align: &Alignments,
+) -> Result<TextMetrics, cairo_rs::Error> {
+    let surface = ImageSurface::create(Format::ARgb32, 1, 1)?;
+    let context = Context::new(&surface)?;
+    let layout = style.layouter(&create_context(&context));
+    text.set(&layout, &context, style.vertical);
+    text.set_font_size(&layout);
+    let align = text.align.as_ref().unwrap_or(align);
+    let (runs, remaining, font_size) = text.fit(&layout, &context, style.vertical, align)?;
+
+    let mut ink = Bounds::default();
+    let mut logical = Bounds::default();
+    let mut baselines = vec![];
+    for run in &runs {
+        match &run.line {
+            None => {
+                let (run_ink, run_logical) = run.layout.pixel_extents();
+                ink.add(run.x, run.y, run_ink);
+                logical.add(run.x, run.y, run_logical);
+                let mut iter = run.layout.iter();
+                loop {
+                    let (_, line) = iter.line_extents();
+                    baselines.push((
+                        run.y + iter.baseline() as f64 / SCALE as f64,
+                        line.width() as f64 / SCALE as f64,
+                    ));
+                    if !iter.next_line() {
+                        break;
+                    }
+                }
+            }
+            Some(line) => {
+                // a line is drawn with its baseline at the position of the run
+                let (line_ink, line_logical) = line.pixel_extents();
+                ink.add(run.x, run.y, line_ink);
+                logical.add(run.x, run.y, line_logical);
+                baselines.push((run.y, line_logical.width() as f64));
+            }
+        }
+    }
+    let top = match logical.rect {
+        Some((_, top, _, _)) => top,
+        None => 0.,
+    };
+    Ok(TextMetrics {
+        ink: ink.to_box(&context, &text.pos),
+        logical: logical.to_box(&context, &text.pos),
+        line_count: baselines.len(),
+        lines: baselines
+            .into_iter()
+            .map(|(baseline, width)| LineMetrics {
+                baseline: baseline - top,
+                width,
+            })
+            .collect(),
+        ellipsized: runs.iter().any(|run| run.layout.is_ellipsized()),
+        overflowed: remaining.is_some(),
+        font_size,
+    })
+}
+
+/// Rectangle around the runs in layout coordinates
+#[derive(Default)]
+struct Bounds {
+    /// left, top, right, bottom
+    rect: Option<(f64, f64, f64, f64)>,
+}
+
+impl Bounds {
+    /// Adds a rectangle of a run at x, y
+    fn add(&mut self, x: f64, y: f64, rect: pango::Rectangle) {
+        let left = x + rect.x() as f64;
+        let top = y + rect.y() as f64;
+        let right = left + rect.width() as f64;
+        let bottom = top + rect.height() as f64;
+        self.rect = Some(match self.rect {
+            None => (left, top, right, bottom),
+            Some(r) => (r.0.min(left), r.1.min(top), r.2.max(right), r.3.max(bottom)),
+        });
+    }
+
+    fn to_box(&self, context: &Context, pos: &Pos2) -> Extents {
+        let (left, top, right, bottom) = self.rect.unwrap_or_default();
+        to_box(context, pos, left, top, right - left, bottom - top)
+    }
+}
+
+/// Converts a rectangle in layout coordinates to the unrotated textblock
+fn to_box(context: &Context, pos: &Pos2, x: f64, y: f64, width: f64, height: f64) -> Extents {
+    let corners = [
+        (x, y),
+        (x + width, y),
+        (x, y + height),
+        (x + width, y + height),
+    ]
+    .map(|(x, y)| context.user_to_device(x, y));
+    let left = corners.iter().map(|c| c.0).fold(f64::INFINITY, f64::min);
+    let top = corners.iter().map(|c| c.1).fold(f64::INFINITY, f64::min);
+    let right = corners
+        .iter()
+        .map(|c| c.0)
+        .fold(f64::NEG_INFINITY, f64::max);
+    let bottom = corners
+        .iter()
+        .map(|c| c.1)
+        .fold(f64::NEG_INFINITY, f64::max);
+    Extents {
+        x: left - pos.x,
+        y: top - pos.y,
+        width: right - left,
+        height: bottom - top,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::measure;
+    use crate::input::{
+        Alignments, Background, Data, HorizontalAlignment, Item, Mode, OutputMode, OverflowPolicy,
+        Paint, Pos2, ReadDirection, Rgba, Size2, Styling, Text, VerticalAlignment, Wrap,
+    };
+
+    fn text(value: &str, size: Size2, font_size: f64, overflow: OverflowPolicy) -> Text {
+        Text {
+            mode: Mode::Text,
+            value: value.to_string(),
+            pos: Pos2::new(10., 10.),
+            size,
+            font_size,
+            font_color: Paint::Solid(Rgba::new(0., 0., 0., 1.)),
+            background: Background::None,
+            style: None,
+            align: None,
+            strokes: vec![],
+            effects: vec![],
+            paint_order: Default::default(),
+            transform: None,
+            path: None,
+            region: Default::default(),
+            frames: vec![],
+            overflow,
+            decoration: Default::default(),
+            quad: None,
+            layer: 0,
+            z_index: 0,
+            opacity: 1.,
+            blend: Default::default(),
+        }
+    }
+
+    fn style() -> Styling {
+        Styling {
+            spacing: None,
+            line_spacing: None,
+            ellipsize: Default::default(),
+            wrap: Wrap::Word,
+            indent: None,
+            single_paragraph_mode: false,
+            auto_dir: true,
+            read_direction: ReadDirection::default(),
+            vertical: false,
+            font: None,
+            justiy: false,
+            justify_last_line: false,
+        }
+    }
+
+    #[test]
+    fn measurements_match_the_painter() {
+        let value = "measured text that is wrapped over several lines";
+        let texts = [
+            text(value, Size2::new(300., 200.), 16., OverflowPolicy::Visible),
+            text(value, Size2::new(80., 30.), 16., OverflowPolicy::Visible),
+            text(value, Size2::new(5., 0.5), 16., OverflowPolicy::Shrink),
+        ];
+        // texts without an alignment use the global one
+        let align = Alignments {
+            ha: HorizontalAlignment::Center,
+            va: VerticalAlignment::Center,
+        };
+        let metrics = texts
+            .iter()
+            .map(|text| measure(text, &style(), &align).unwrap())
+            .collect::<Vec<_>>();
+        let data = Data {
+            items: texts.into_iter().map(Item::Text).collect(),
+            global_style: style(),
+            global_align: align,
+            background: None,
+            layers: vec![],
+        };
+        let painter = data
+            .painter(&OutputMode::Png(false), Some(&Size2::new(400., 300.)))
+            .unwrap();
+
+        assert!(!metrics[0].overflowed);
+        assert!(metrics[1].overflowed && metrics[2].overflowed);
+        for (index, metrics) in metrics.iter().enumerate() {
+            let annotation = &painter.annotations()[index];
+            assert_eq!(annotation.item, index);
+            assert_eq!(metrics.line_count, annotation.lines.len());
+            let overflow = painter.overflows().iter().find(|o| o.item == index);
+            assert_eq!(metrics.overflowed, overflow.is_some());
+            if let Some(overflow) = overflow {
+                assert_eq!(metrics.font_size, overflow.font_size);
+            }
+        }
+        assert!(metrics[2].font_size < 16.);
+    }
+}
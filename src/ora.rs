@@ -0,0 +1,399 @@
+use std::io::{Cursor, Write};
+
+use cairo_rs::{Context, Format, ImageSurface};
+use image::{ImageOutputFormat, RgbaImage};
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use crate::effects::pixels;
+use crate::input::{BlendMode, Layer};
+use crate::raster::to_image;
+
+/// Largest width and height of the thumbnail
+const THUMBNAIL_SIZE: u32 = 256;
+
+/// Pass of the painter kept as a layer of the OpenRaster export
+struct RasterLayer {
+    name: String,
+    /// upper left corner of the image on the canvas
+    x: u32,
+    y: u32,
+    image: RgbaImage,
+    opacity: f64,
+    blend: &'static str,
+}
+
+/// Layer of the scene kept as a stack of its passes
+struct Stack {
+    name: String,
+    visible: bool,
+    opacity: f64,
+    blend: &'static str,
+    /// bottom pass first
+    layers: Vec<RasterLayer>,
+}
+
+/// Layers of an OpenRaster export, collected while the painter draws
+pub(crate) struct Capture {
+    width: i32,
+    height: i32,
+    background: Option<RasterLayer>,
+    /// one for every layer of the scene, by index
+    stacks: Vec<Stack>,
+}
+
+impl Stack {
+    fn new(index: usize, layer: &Layer) -> Stack {
+        Stack {
+            name: match layer.name.is_empty() {
+                true => format!("layer {}", index),
+                false => layer.name.clone(),
+            },
+            visible: layer.visible,
+            opacity: layer.opacity.clamp(0., 1.),
+            blend: layer.blend.composite_op(),
+            layers: vec![],
+        }
+    }
+}
+
+impl BlendMode {
+    /// composite-op of the blend mode in stack.xml
+    fn composite_op(&self) -> &'static str {
+        match self {
+            BlendMode::Normal => "svg:src-over",
+            BlendMode::Multiply => "svg:multiply",
+            BlendMode::Screen => "svg:screen",
+            BlendMode::Overlay => "svg:overlay",
+            BlendMode::Darken => "svg:darken",
+            BlendMode::Lighten => "svg:lighten",
+            BlendMode::ColorDodge => "svg:color-dodge",
+            BlendMode::ColorBurn => "svg:color-burn",
+            BlendMode::HardLight => "svg:hard-light",
+            BlendMode::SoftLight => "svg:soft-light",
+            BlendMode::Difference => "svg:difference",
+            BlendMode::Exclusion => "svg:exclusion",
+            BlendMode::Hue => "svg:hue",
+            BlendMode::Saturation => "svg:saturation",
+            BlendMode::Color => "svg:color",
+            BlendMode::Luminosity => "svg:luminosity",
+        }
+    }
+}
+
+impl Capture {
+    /// layers: the layers of the scene, each gets its own stack
+    pub(crate) fn new(width: i32, height: i32, layers: &[Layer]) -> Capture {
+        Capture {
+            width,
+            height,
+            background: None,
+            stacks: layers
+                .iter()
+                .enumerate()
+                .map(|(index, layer)| Stack::new(index, layer))
+                .collect(),
+        }
+    }
+
+    /// Starts a pass, what is drawn goes into a group
+    pub(crate) fn begin(&self, context: &Context) {
+        context.push_group();
+    }
+
+    /// Ends the pass, keeps the group in the stack of the layer
+    /// The group is painted onto the context with paint unless the layer is hidden
+    /// opacity, blend: how the pass is combined in the export
+    pub(crate) fn end(
+        &mut self,
+        context: &Context,
+        layer: usize,
+        name: String,
+        opacity: f64,
+        blend: &BlendMode,
+        paint: &BlendMode,
+    ) -> Result<(), cairo_rs::Error> {
+        // items on a layer without settings are in a default layer
+        while self.stacks.len() <= layer {
+            self.stacks
+                .push(Stack::new(self.stacks.len(), &Layer::default()));
+        }
+        let pattern = context.pop_group()?;
+        if self.stacks[layer].visible {
+            context.save()?;
+            context.set_source(&pattern)?;
+            context.set_operator(paint.to_cairo());
+            context.paint()?;
+            context.restore()?;
+        }
+
+        let surface = ImageSurface::create(Format::ARgb32, self.width, self.height)?;
+        {
+            let layer_context = Context::new(&surface)?;
+            layer_context.set_matrix(context.matrix());
+            layer_context.set_source(&pattern)?;
+            layer_context.paint()?;
+        }
+        if let Some(pass) = self.crop(surface, name, opacity, blend)? {
+            self.stacks[layer].layers.push(pass);
+        }
+        Ok(())
+    }
+
+    /// Keeps a copy of what is drawn on the surface so far as the bottom layer
+    pub(crate) fn snapshot(
+        &mut self,
+        surface: &ImageSurface,
+        name: String,
+    ) -> Result<(), cairo_rs::Error> {
+        let layer = ImageSurface::create(Format::ARgb32, self.width, self.height)?;
+        {
+            let layer_context = Context::new(&layer)?;
+            layer_context.set_source_surface(surface, 0., 0.)?;
+            layer_context.paint()?;
+        }
+        self.background = self.crop(layer, name, 1., &BlendMode::Normal)?;
+        Ok(())
+    }
+
+    /// The drawn part of the surface as a layer, None for an empty surface
+    fn crop(
+        &self,
+        mut surface: ImageSurface,
+        name: String,
+        opacity: f64,
+        blend: &BlendMode,
+    ) -> Result<Option<RasterLayer>, cairo_rs::Error> {
+        let stride = surface.stride() as usize;
+        let image = to_image(
+            &pixels(&mut surface)?,
+            self.width as u32,
+            self.height as u32,
+            stride,
+        );
+        let (mut left, mut top, mut right, mut bottom) = (u32::MAX, u32::MAX, 0, 0);
+        for (x, y, pixel) in image.enumerate_pixels() {
+            if pixel.0[3] > 0 {
+                left = left.min(x);
+                top = top.min(y);
+                right = right.max(x + 1);
+                bottom = bottom.max(y + 1);
+            }
+        }
+        if right <= left || bottom <= top {
+            return Ok(None);
+        }
+        Ok(Some(RasterLayer {
+            name,
+            x: left,
+            y: top,
+            image: image::imageops::crop_imm(&image, left, top, right - left, bottom - top)
+                .to_image(),
+            opacity: opacity.clamp(0., 1.),
+            blend: blend.composite_op(),
+        }))
+    }
+
+    /// Passes in the order of their files, bottom first
+    fn layers(&self) -> impl Iterator<Item = &RasterLayer> {
+        self.background
+            .iter()
+            .chain(self.stacks.iter().flat_map(|stack| &stack.layers))
+    }
+
+    /// Writes the layers, the merged image and a thumbnail as an OpenRaster zip
+    pub(crate) fn write<W: Write>(
+        &self,
+        merged: &ImageSurface,
+        writer: &mut W,
+    ) -> Result<(), String> {
+        let mut merged_png = vec![];
+        merged
+            .write_to_png(&mut merged_png)
+            .map_err(|e| e.to_string())?;
+        let thumbnail = image::load_from_memory(&merged_png)
+            .map_err(|e| e.to_string())?
+            .thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE);
+
+        let mut zip = ZipWriter::new(Cursor::new(vec![]));
+        // the mimetype comes first and uncompressed so the format can be recognised
+        let stored = FileOptions::default().compression_method(CompressionMethod::Stored);
+        let deflated = FileOptions::default();
+        zip.start_file("mimetype", stored)
+            .map_err(|e| e.to_string())?;
+        zip.write_all(b"image/openraster")
+            .map_err(|e| e.to_string())?;
+        zip.start_file("stack.xml", deflated)
+            .map_err(|e| e.to_string())?;
+        zip.write_all(self.stack().as_bytes())
+            .map_err(|e| e.to_string())?;
+        for (index, layer) in self.layers().enumerate() {
+            zip.start_file(format!("data/layer{}.png", index), stored)
+                .map_err(|e| e.to_string())?;
+            let mut png = Cursor::new(vec![]);
+            layer
+                .image
+                .write_to(&mut png, ImageOutputFormat::Png)
+                .map_err(|e| e.to_string())?;
+            zip.write_all(&png.into_inner())
+                .map_err(|e| e.to_string())?;
+        }
+        zip.start_file("mergedimage.png", stored)
+            .map_err(|e| e.to_string())?;
+        zip.write_all(&merged_png).map_err(|e| e.to_string())?;
+        zip.start_file("Thumbnails/thumbnail.png", stored)
+            .map_err(|e| e.to_string())?;
+        let mut png = Cursor::new(vec![]);
+        thumbnail
+            .write_to(&mut png, ImageOutputFormat::Png)
+            .map_err(|e| e.to_string())?;
+        zip.write_all(&png.into_inner())
+            .map_err(|e| e.to_string())?;
+        let bytes = zip.finish().map_err(|e| e.to_string())?.into_inner();
+        writer.write_all(&bytes).map_err(|e| e.to_string())
+    }
+
+    /// stack.xml with the top layer first, every layer of the scene is a stack of its passes
+    fn stack(&self) -> String {
+        let mut xml = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<image version=\"0.0.5\" w=\"{}\" h=\"{}\">\n<stack>\n",
+            self.width, self.height
+        );
+        // files are numbered bottom first
+        let mut index = self.layers().count();
+        for stack in self.stacks.iter().rev() {
+            xml.push_str(&format!(
+                "<stack name=\"{}\" opacity=\"{}\" composite-op=\"{}\" visibility=\"{}\">\n",
+                escape(&stack.name),
+                stack.opacity,
+                stack.blend,
+                visibility(stack.visible)
+            ));
+            for layer in stack.layers.iter().rev() {
+                index -= 1;
+                xml.push_str(&layer_element(layer, index));
+            }
+            xml.push_str("</stack>\n");
+        }
+        if let Some(background) = &self.background {
+            xml.push_str(&layer_element(background, 0));
+        }
+        xml.push_str("</stack>\n</image>\n");
+        xml
+    }
+}
+
+/// Element of a pass in stack.xml, index: number of its file
+fn layer_element(layer: &RasterLayer, index: usize) -> String {
+    format!(
+        "<layer name=\"{}\" src=\"data/layer{}.png\" x=\"{}\" y=\"{}\" opacity=\"{}\" composite-op=\"{}\" visibility=\"visible\"/>\n",
+        escape(&layer.name),
+        index,
+        layer.x,
+        layer.y,
+        layer.opacity,
+        layer.blend
+    )
+}
+
+/// Value of the visibility attribute
+fn visibility(visible: bool) -> &'static str {
+    match visible {
+        true => "visible",
+        false => "hidden",
+    }
+}
+
+/// Escapes the text for an xml attribute
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use cairo_rs::{Context, Format, ImageSurface};
+
+    use super::Capture;
+    use crate::input::{BlendMode, Layer};
+
+    /// Capture of a visible, a hidden and an unset layer with one square each
+    fn capture(surface: &ImageSurface) -> Capture {
+        let layers = [
+            Layer {
+                name: "ink".to_string(),
+                visible: true,
+                opacity: 0.5,
+                blend: BlendMode::Multiply,
+            },
+            Layer {
+                name: "notes".to_string(),
+                visible: false,
+                ..Default::default()
+            },
+        ];
+        let mut capture = Capture::new(20, 20, &layers);
+        let context = Context::new(surface).unwrap();
+        for (layer, x) in [(0, 0.), (1, 10.), (3, 5.)] {
+            capture.begin(&context);
+            context.rectangle(x, 0., 5., 5.);
+            context.fill().unwrap();
+            capture
+                .end(
+                    &context,
+                    layer,
+                    format!("item {}", layer),
+                    1.,
+                    &BlendMode::Normal,
+                    &BlendMode::Normal,
+                )
+                .unwrap();
+        }
+        capture
+    }
+
+    #[test]
+    fn every_layer_is_a_stack() {
+        let surface = ImageSurface::create(Format::ARgb32, 20, 20).unwrap();
+        let xml = capture(&surface).stack();
+        assert_eq!(xml.matches("<stack").count(), 5);
+        assert_eq!(xml.matches("<layer ").count(), 3);
+        assert!(xml.contains(
+            "<stack name=\"ink\" opacity=\"0.5\" composite-op=\"svg:multiply\" visibility=\"visible\">"
+        ));
+        assert!(xml.contains("<stack name=\"notes\" opacity=\"1\" composite-op=\"svg:src-over\" visibility=\"hidden\">"));
+        assert!(xml.contains("<stack name=\"layer 2\""));
+        // the top layer comes first
+        let position = |name: &str| xml.find(name).unwrap();
+        assert!(position("layer 3") < position("notes") && position("notes") < position("ink"));
+        assert!(xml.contains("name=\"item 3\" src=\"data/layer2.png\" x=\"5\" y=\"0\""));
+    }
+
+    #[test]
+    fn hidden_layers_are_only_exported() {
+        let mut surface = ImageSurface::create(Format::ARgb32, 20, 20).unwrap();
+        let capture = capture(&surface);
+        let stride = surface.stride() as usize;
+        let data = surface.data().unwrap();
+        let alpha = |x: usize| {
+            let i = stride + x * 4;
+            u32::from_ne_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]) >> 24
+        };
+        assert_eq!((alpha(1), alpha(11), alpha(8)), (255, 0, 255));
+        drop(data);
+
+        let mut ora = vec![];
+        capture.write(&surface, &mut ora).unwrap();
+        let mut zip = zip::ZipArchive::new(Cursor::new(ora)).unwrap();
+        assert_eq!(zip.by_index(0).unwrap().name(), "mimetype");
+        for index in 0..3 {
+            assert!(zip.by_name(&format!("data/layer{}.png", index)).is_ok());
+        }
+        assert!(zip.by_name("data/layer3.png").is_err());
+        assert!(zip.by_name("stack.xml").is_ok());
+    }
+}
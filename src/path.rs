@@ -0,0 +1,540 @@
+use std::f64::consts::PI;
+
+use cairo_rs::{Context, PathSegment};
+use pango::{Layout, SCALE};
+use pangocairo::layout_path;
+
+use crate::input::{Curve, HorizontalAlignment, PathSide, Pos2, TextPath};
+
+/// Longest straight piece of a glyph outline that is not bent along the curve
+const MAX_SEGMENT: f64 = 2.;
+
+impl Curve {
+    /// Adds the curve to the path of the context, points are relative to origin
+    pub(crate) fn append(&self, context: &Context, origin: &Pos2) -> Result<(), cairo_rs::Error> {
+        match self {
+            Curve::Arc {
+                center,
+                radius,
+                start_angle,
+                end_angle,
+            } => {
+                context.new_sub_path();
+                let (x, y) = (origin.x + center.x, origin.y + center.y);
+                if end_angle >= start_angle {
+                    context.arc(
+                        x,
+                        y,
+                        *radius,
+                        start_angle.to_radians(),
+                        end_angle.to_radians(),
+                    );
+                } else {
+                    context.arc_negative(
+                        x,
+                        y,
+                        *radius,
+                        start_angle.to_radians(),
+                        end_angle.to_radians(),
+                    );
+                }
+            }
+            Curve::Circle {
+                center,
+                radius,
+                start_angle,
+            } => {
+                context.new_sub_path();
+                let start = start_angle.to_radians();
+                context.arc(
+                    origin.x + center.x,
+                    origin.y + center.y,
+                    *radius,
+                    start,
+                    start + 2. * PI,
+                );
+            }
+            Curve::Svg(data) => {
+                context.save()?;
+                context.translate(origin.x, origin.y);
+                let result = svg_path(context, data);
+                context.restore()?;
+                result?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl TextPath {
+    /// Adds the outline of the layout bent along the curve to the path of the context
+    pub(crate) fn trace(
+        &self,
+        layout: &Layout,
+        context: &Context,
+        origin: &Pos2,
+    ) -> Result<(), cairo_rs::Error> {
+        let map = self.mapping(layout, context, origin)?;
+        context.new_path();
+        context.move_to(0., 0.);
+        layout_path(context, layout);
+        let glyphs = context.copy_path_flat()?;
+        context.new_path();
+
+        let mut first = (0., 0.);
+        let mut last = (0., 0.);
+        for segment in glyphs.iter() {
+            match segment {
+                PathSegment::MoveTo(point) => {
+                    let (x, y) = map(point);
+                    context.move_to(x, y);
+                    first = point;
+                    last = point;
+                }
+                PathSegment::LineTo(point) | PathSegment::CurveTo(_, _, point) => {
+                    bent_line_to(context, &map, last, point);
+                    last = point;
+                }
+                PathSegment::ClosePath => {
+                    bent_line_to(context, &map, last, first);
+                    context.close_path();
+                    last = first;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Maps a point of the layout to its position along the curve
+    pub(crate) fn mapping(
+        &self,
+        layout: &Layout,
+        context: &Context,
+        origin: &Pos2,
+    ) -> Result<impl Fn((f64, f64)) -> (f64, f64), cairo_rs::Error> {
+        context.new_path();
+        self.curve.append(context, origin)?;
+        let mut line = Polyline::from_context(context)?;
+        context.new_path();
+        if let PathSide::Right = self.side {
+            line.reverse();
+        }
+
+        let baseline = layout.baseline() as f64 / SCALE as f64;
+        let width = layout.pixel_size().0 as f64;
+        let start = self.start_offset
+            + match self.align {
+                HorizontalAlignment::Left => 0.,
+                HorizontalAlignment::Center => (line.length() - width) / 2.,
+                HorizontalAlignment::Right => line.length() - width,
+            };
+        Ok(move |(x, y): (f64, f64)| line.map(start + x, y - baseline))
+    }
+}
+
+/// Splits the line into short pieces so it follows the curve
+pub(crate) fn bent_line_to(
+    context: &Context,
+    map: &impl Fn((f64, f64)) -> (f64, f64),
+    from: (f64, f64),
+    to: (f64, f64),
+) {
+    let length = ((to.0 - from.0).powi(2) + (to.1 - from.1).powi(2)).sqrt();
+    let steps = (length / MAX_SEGMENT).ceil().max(1.) as usize;
+    for i in 1..=steps {
+        let t = i as f64 / steps as f64;
+        let (x, y) = map((from.0 + (to.0 - from.0) * t, from.1 + (to.1 - from.1) * t));
+        context.line_to(x, y);
+    }
+}
+
+/// Flattened curve with the distance of every point from the start
+pub(crate) struct Polyline {
+    points: Vec<(f64, f64)>,
+    distances: Vec<f64>,
+}
+
+impl Polyline {
+    /// Flattens the current path of the context, subpaths are joined
+    pub(crate) fn from_context(context: &Context) -> Result<Polyline, cairo_rs::Error> {
+        let path = context.copy_path_flat()?;
+        let mut line = Polyline {
+            points: vec![],
+            distances: vec![],
+        };
+        let mut first = None;
+        for segment in path.iter() {
+            match segment {
+                PathSegment::MoveTo(point) => {
+                    first = Some(point);
+                    line.push(point);
+                }
+                PathSegment::LineTo(point) | PathSegment::CurveTo(_, _, point) => line.push(point),
+                PathSegment::ClosePath => {
+                    if let Some(point) = first {
+                        line.push(point);
+                    }
+                }
+            }
+        }
+        Ok(line)
+    }
+
+    fn push(&mut self, point: (f64, f64)) {
+        match self.points.last() {
+            None => self.distances.push(0.),
+            Some(last) => {
+                let step = ((point.0 - last.0).powi(2) + (point.1 - last.1).powi(2)).sqrt();
+                if step == 0. {
+                    return;
+                }
+                self.distances.push(self.length() + step);
+            }
+        }
+        self.points.push(point);
+    }
+
+    pub(crate) fn length(&self) -> f64 {
+        self.distances.last().copied().unwrap_or(0.)
+    }
+
+    pub(crate) fn reverse(&mut self) {
+        let length = self.length();
+        self.points.reverse();
+        self.distances.reverse();
+        for distance in &mut self.distances {
+            *distance = length - *distance;
+        }
+    }
+
+    /// Point at distance along the line, moved by offset to the right of the direction
+    /// Extends the first and last piece beyond the ends
+    pub(crate) fn map(&self, distance: f64, offset: f64) -> (f64, f64) {
+        if self.points.len() < 2 {
+            let (x, y) = self.points.first().copied().unwrap_or((0., 0.));
+            return (x + distance, y + offset);
+        }
+        let i = self
+            .distances
+            .partition_point(|&d| d <= distance)
+            .clamp(1, self.points.len() - 1);
+        let (x0, y0) = self.points[i - 1];
+        let (x1, y1) = self.points[i];
+        let length = self.distances[i] - self.distances[i - 1];
+        let (dx, dy) = ((x1 - x0) / length, (y1 - y0) / length);
+        let along = distance - self.distances[i - 1];
+        (x0 + dx * along - dy * offset, y0 + dy * along + dx * offset)
+    }
+}
+
+/// Adds svg path data to the path of the context
+/// Supports all commands of the svg path syntax
+pub(crate) fn svg_path(context: &Context, data: &str) -> Result<(), cairo_rs::Error> {
+    let mut tokens = Tokens {
+        data: data.as_bytes(),
+        pos: 0,
+    };
+    let mut command = match tokens.command() {
+        Some(command) => command,
+        None if tokens.at_end() => return Ok(()),
+        None => return Err(cairo_rs::Error::InvalidPathData),
+    };
+    let mut current = (0., 0.);
+    let mut start = (0., 0.);
+    let mut cubic: Option<(f64, f64)> = None;
+    let mut quad: Option<(f64, f64)> = None;
+    loop {
+        let base = match command.is_ascii_lowercase() {
+            true => current,
+            false => (0., 0.),
+        };
+        let mut next_cubic = None;
+        let mut next_quad = None;
+        match command.to_ascii_uppercase() {
+            b'M' => {
+                current = tokens.point(base)?;
+                start = current;
+                context.move_to(current.0, current.1);
+                command = match command.is_ascii_lowercase() {
+                    true => b'l',
+                    false => b'L',
+                };
+            }
+            b'L' => {
+                current = tokens.point(base)?;
+                context.line_to(current.0, current.1);
+            }
+            b'H' => {
+                current.0 = tokens.number()? + base.0;
+                context.line_to(current.0, current.1);
+            }
+            b'V' => {
+                current.1 = tokens.number()? + base.1;
+                context.line_to(current.0, current.1);
+            }
+            b'C' | b'S' => {
+                let c1 = match command.to_ascii_uppercase() {
+                    b'C' => tokens.point(base)?,
+                    _ => reflect(cubic, current),
+                };
+                let c2 = tokens.point(base)?;
+                current = tokens.point(base)?;
+                context.curve_to(c1.0, c1.1, c2.0, c2.1, current.0, current.1);
+                next_cubic = Some(c2);
+            }
+            b'Q' | b'T' => {
+                let q = match command.to_ascii_uppercase() {
+                    b'Q' => tokens.point(base)?,
+                    _ => reflect(quad, current),
+                };
+                let from = current;
+                current = tokens.point(base)?;
+                context.curve_to(
+                    from.0 + (q.0 - from.0) * 2. / 3.,
+                    from.1 + (q.1 - from.1) * 2. / 3.,
+                    current.0 + (q.0 - current.0) * 2. / 3.,
+                    current.1 + (q.1 - current.1) * 2. / 3.,
+                    current.0,
+                    current.1,
+                );
+                next_quad = Some(q);
+            }
+            b'A' => {
+                let rx = tokens.number()?;
+                let ry = tokens.number()?;
+                let rotation = tokens.number()?;
+                let large = tokens.flag()?;
+                let sweep = tokens.flag()?;
+                let from = current;
+                current = tokens.point(base)?;
+                arc_to(context, from, (rx, ry), rotation, large, sweep, current)?;
+            }
+            b'Z' => {
+                context.close_path();
+                current = start;
+            }
+            _ => return Err(cairo_rs::Error::InvalidPathData),
+        }
+        cubic = next_cubic;
+        quad = next_quad;
+
+        if tokens.at_end() {
+            return Ok(());
+        }
+        if let Some(next) = tokens.command() {
+            command = next;
+        } else if command.to_ascii_uppercase() == b'Z' {
+            return Err(cairo_rs::Error::InvalidPathData);
+        }
+    }
+}
+
+/// Mirrors the last control point at the current point
+fn reflect(control: Option<(f64, f64)>, current: (f64, f64)) -> (f64, f64) {
+    match control {
+        Some((x, y)) => (2. * current.0 - x, 2. * current.1 - y),
+        None => current,
+    }
+}
+
+/// Elliptical arc in endpoint notation of svg
+fn arc_to(
+    context: &Context,
+    from: (f64, f64),
+    radius: (f64, f64),
+    rotation: f64,
+    large: bool,
+    sweep: bool,
+    to: (f64, f64),
+) -> Result<(), cairo_rs::Error> {
+    let (mut rx, mut ry) = (radius.0.abs(), radius.1.abs());
+    if from == to {
+        return Ok(());
+    }
+    if rx == 0. || ry == 0. {
+        context.line_to(to.0, to.1);
+        return Ok(());
+    }
+    let (sin, cos) = rotation.to_radians().sin_cos();
+    let dx = (from.0 - to.0) / 2.;
+    let dy = (from.1 - to.1) / 2.;
+    let x1 = cos * dx + sin * dy;
+    let y1 = -sin * dx + cos * dy;
+    let lambda = x1 * x1 / (rx * rx) + y1 * y1 / (ry * ry);
+    if lambda > 1. {
+        rx *= lambda.sqrt();
+        ry *= lambda.sqrt();
+    }
+    let numerator = rx * rx * ry * ry - rx * rx * y1 * y1 - ry * ry * x1 * x1;
+    let denominator = rx * rx * y1 * y1 + ry * ry * x1 * x1;
+    let mut coefficient = (numerator / denominator).max(0.).sqrt();
+    if large == sweep {
+        coefficient = -coefficient;
+    }
+    let cx1 = coefficient * rx * y1 / ry;
+    let cy1 = -coefficient * ry * x1 / rx;
+    let cx = cos * cx1 - sin * cy1 + (from.0 + to.0) / 2.;
+    let cy = sin * cx1 + cos * cy1 + (from.1 + to.1) / 2.;
+
+    let angle = |u: (f64, f64), v: (f64, f64)| (u.0 * v.1 - u.1 * v.0).atan2(u.0 * v.0 + u.1 * v.1);
+    let u = ((x1 - cx1) / rx, (y1 - cy1) / ry);
+    let v = ((-x1 - cx1) / rx, (-y1 - cy1) / ry);
+    let theta = angle((1., 0.), u);
+    let mut delta = angle(u, v);
+    if !sweep && delta > 0. {
+        delta -= 2. * PI;
+    } else if sweep && delta < 0. {
+        delta += 2. * PI;
+    }
+
+    context.save()?;
+    context.translate(cx, cy);
+    context.rotate(rotation.to_radians());
+    context.scale(rx, ry);
+    if sweep {
+        context.arc(0., 0., 1., theta, theta + delta);
+    } else {
+        context.arc_negative(0., 0., 1., theta, theta + delta);
+    }
+    context.restore()
+}
+
+struct Tokens<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Tokens<'_> {
+    fn skip(&mut self) {
+        while self.pos < self.data.len()
+            && (self.data[self.pos].is_ascii_whitespace() || self.data[self.pos] == b',')
+        {
+            self.pos += 1;
+        }
+    }
+
+    fn at_end(&mut self) -> bool {
+        self.skip();
+        self.pos >= self.data.len()
+    }
+
+    fn command(&mut self) -> Option<u8> {
+        self.skip();
+        let command = *self.data.get(self.pos)?;
+        if command.is_ascii_alphabetic() {
+            self.pos += 1;
+            return Some(command);
+        }
+        None
+    }
+
+    fn number(&mut self) -> Result<f64, cairo_rs::Error> {
+        self.skip();
+        let start = self.pos;
+        let digits = |tokens: &mut Self| {
+            while tokens.pos < tokens.data.len() && tokens.data[tokens.pos].is_ascii_digit() {
+                tokens.pos += 1;
+            }
+        };
+        if matches!(self.data.get(self.pos), Some(b'+') | Some(b'-')) {
+            self.pos += 1;
+        }
+        digits(self);
+        if self.data.get(self.pos) == Some(&b'.') {
+            self.pos += 1;
+            digits(self);
+        }
+        if matches!(self.data.get(self.pos), Some(b'e') | Some(b'E')) {
+            self.pos += 1;
+            if matches!(self.data.get(self.pos), Some(b'+') | Some(b'-')) {
+                self.pos += 1;
+            }
+            digits(self);
+        }
+        std::str::from_utf8(&self.data[start..self.pos])
+            .ok()
+            .and_then(|number| number.parse().ok())
+            .ok_or(cairo_rs::Error::InvalidPathData)
+    }
+
+    fn point(&mut self, base: (f64, f64)) -> Result<(f64, f64), cairo_rs::Error> {
+        Ok((self.number()? + base.0, self.number()? + base.1))
+    }
+
+    fn flag(&mut self) -> Result<bool, cairo_rs::Error> {
+        self.skip();
+        let flag = match self.data.get(self.pos) {
+            Some(b'0') => false,
+            Some(b'1') => true,
+            _ => return Err(cairo_rs::Error::InvalidPathData),
+        };
+        self.pos += 1;
+        Ok(flag)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cairo_rs::{Context, Format, ImageSurface};
+
+    use super::svg_path;
+
+    fn context() -> Context {
+        let surface = ImageSurface::create(Format::ARgb32, 10, 10).unwrap();
+        Context::new(&surface).unwrap()
+    }
+
+    #[test]
+    fn absolute_and_relative_commands() {
+        let context = context();
+        svg_path(&context, "M 1 2 L 5,2 h 3 v-4").unwrap();
+        assert_eq!(context.current_point().unwrap(), (8., -2.));
+        svg_path(&context, "m1 1 2 2").unwrap();
+        assert_eq!(context.current_point().unwrap(), (3., 3.));
+    }
+
+    #[test]
+    fn close_returns_to_start() {
+        let context = context();
+        svg_path(&context, "M 3 4 L 9 4 L 9 8 Z").unwrap();
+        assert_eq!(context.current_point().unwrap(), (3., 4.));
+    }
+
+    #[test]
+    fn numbers_without_separators() {
+        let context = context();
+        svg_path(&context, "M1.5.5L-2e1-3").unwrap();
+        assert_eq!(context.current_point().unwrap(), (-20., -3.));
+    }
+
+    #[test]
+    fn curves_and_arcs_end_at_their_point() {
+        let context = context();
+        svg_path(&context, "M0 0 C 1 1 2 1 3 0 S 5 -1 6 0 Q 7 1 8 0 T 10 0").unwrap();
+        assert_eq!(context.current_point().unwrap(), (10., 0.));
+        svg_path(&context, "A 5 5 0 0 1 20 0").unwrap();
+        let (x, y) = context.current_point().unwrap();
+        // cairo keeps the path in fixed point
+        assert!((x - 20.).abs() < 0.01 && y.abs() < 0.01);
+    }
+
+    #[test]
+    fn empty_data_adds_nothing() {
+        let context = context();
+        svg_path(&context, "  ").unwrap();
+        assert!(!context.has_current_point().unwrap());
+    }
+
+    #[test]
+    fn invalid_data_is_an_error() {
+        for data in [
+            "1 2",
+            "M 1",
+            "M 1 2 X 3",
+            "M 0 0 A 1 1 0 2 0 3 3",
+            "M 0 0 Z 1",
+        ] {
+            assert!(svg_path(&context(), data).is_err(), "{}", data);
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use cairo_rs::{Context, Format, ImageSurface, PathSegment, SurfaceType};
+
+use crate::cairopango::{ChosenColors, PainterError, Run};
+use crate::effects::pixels;
+use crate::input::Text;
+use crate::path::bent_line_to;
+use crate::raster::{bilinear, from_image, homography, project, to_image};
+
+impl Text {
+    /// Homography from the textblock to the quad in output coordinates
+    pub(crate) fn projection(&self) -> Option<[f64; 9]> {
+        homography(&self.corners(), &self.quad_corners()?)
+    }
+
+    /// Corners of the textblock: top left, top right, bottom right, bottom left
+    fn corners(&self) -> [(f64, f64); 4] {
+        let (x, y, w, h) = (self.pos.x, self.pos.y, self.size.width, self.size.height);
+        [(x, y), (x + w, y), (x + w, y + h), (x, y + h)]
+    }
+
+    fn quad_corners(&self) -> Option<[(f64, f64); 4]> {
+        let quad = self.quad.as_ref()?;
+        Some(quad.each_ref().map(|corner| (corner.x, corner.y)))
+    }
+
+    /// Vector output projects the glyph paths, raster output is resampled
+    pub(crate) fn projects_paths(&self, context: &Context) -> bool {
+        self.quad.is_some() && !matches!(context.target().type_(), SurfaceType::Image)
+    }
+
+    /// Replaces the path of the context with its projection onto the quad
+    pub(crate) fn project_path(&self, context: &Context) -> Result<(), cairo_rs::Error> {
+        let projection = match self.projection() {
+            Some(projection) => projection,
+            None => return Ok(()),
+        };
+        let path = context.copy_path_flat()?;
+        context.new_path();
+        let matrix = context.matrix();
+        let map = |(x, y): (f64, f64)| project(&projection, matrix.transform_point(x, y));
+
+        context.save()?;
+        context.identity_matrix();
+        let mut first = (0., 0.);
+        let mut last = (0., 0.);
+        for segment in path.iter() {
+            match segment {
+                PathSegment::MoveTo(point) => {
+                    let (x, y) = map(point);
+                    context.move_to(x, y);
+                    first = point;
+                    last = point;
+                }
+                PathSegment::LineTo(point) | PathSegment::CurveTo(_, _, point) => {
+                    bent_line_to(context, &map, last, point);
+                    last = point;
+                }
+                PathSegment::ClosePath => {
+                    bent_line_to(context, &map, last, first);
+                    context.close_path();
+                    last = first;
+                }
+            }
+        }
+        context.restore()
+    }
+
+    /// Adds the quad to the path of the context
+    pub(crate) fn quad_path(&self, context: &Context) -> Result<(), cairo_rs::Error> {
+        if let Some(quad) = &self.quad {
+            context.save()?;
+            context.identity_matrix();
+            context.move_to(quad[0].x, quad[0].y);
+            for corner in &quad[1..] {
+                context.line_to(corner.x, corner.y);
+            }
+            context.close_path();
+            context.restore()?;
+        }
+        Ok(())
+    }
+
+    /// Draws the item into a layer of the size of the textblock and resamples it onto the quad
+    /// The layer is not clipped by the output, so parts of the textblock outside of it are kept
+    pub(crate) fn paint_projected(
+        &self,
+        runs: &[Run],
+        context: &Context,
+        width: i32,
+        height: i32,
+        colors: Option<&ChosenColors>,
+    ) -> Result<(), PainterError> {
+        let quad = match self.quad_corners() {
+            Some(quad) => quad,
+            None => return self.paint(runs, context, 1., colors),
+        };
+        // maps the output back onto the textblock, a degenerate quad draws nothing
+        let inverse = match homography(&quad, &self.corners()) {
+            Some(inverse) => inverse,
+            None => return Ok(()),
+        };
+
+        // one pixel around the textblock so the resampling blends its edges
+        let (layer_x, layer_y) = (self.pos.x.floor() - 1., self.pos.y.floor() - 1.);
+        let layer_width = ((self.pos.x + self.size.width).ceil() + 1. - layer_x).max(1.) as i32;
+        let layer_height = ((self.pos.y + self.size.height).ceil() + 1. - layer_y).max(1.) as i32;
+        let mut layer = ImageSurface::create(Format::ARgb32, layer_width, layer_height)?;
+        {
+            let layer_context = Context::new(&layer)?;
+            layer_context.translate(-layer_x, -layer_y);
+            layer_context.transform(context.matrix());
+            self.paint(runs, &layer_context, 1., colors)?;
+        }
+        let stride = layer.stride() as usize;
+        let source = to_image(
+            &pixels(&mut layer)?,
+            layer_width as u32,
+            layer_height as u32,
+            stride,
+        );
+
+        let left = quad
+            .iter()
+            .map(|c| c.0)
+            .fold(f64::INFINITY, f64::min)
+            .floor()
+            .max(0.);
+        let top = quad
+            .iter()
+            .map(|c| c.1)
+            .fold(f64::INFINITY, f64::min)
+            .floor()
+            .max(0.);
+        let right = quad
+            .iter()
+            .map(|c| c.0)
+            .fold(f64::NEG_INFINITY, f64::max)
+            .ceil()
+            .min(width as f64);
+        let bottom = quad
+            .iter()
+            .map(|c| c.1)
+            .fold(f64::NEG_INFINITY, f64::max)
+            .ceil()
+            .min(height as f64);
+        if right <= left || bottom <= top {
+            return Ok(());
+        }
+
+        let (left, top) = (left as u32, top as u32);
+        let mut target = image::RgbaImage::new(right as u32 - left, bottom as u32 - top);
+        for (x, y, pixel) in target.enumerate_pixels_mut() {
+            let point = ((left + x) as f64 + 0.5, (top + y) as f64 + 0.5);
+            let (sx, sy) = project(&inverse, point);
+            *pixel = bilinear(&source, sx - layer_x - 0.5, sy - layer_y - 0.5);
+        }
+        let (target_width, target_height) = target.dimensions();
+        let mut warped =
+            ImageSurface::create(Format::ARgb32, target_width as i32, target_height as i32)?;
+        let stride = warped.stride() as usize;
+        from_image(&target, &mut pixels(&mut warped)?, stride);
+
+        context.save()?;
+        context.identity_matrix();
+        context.set_source_surface(&warped, left as f64, top as f64)?;
+        context.paint()?;
+        context.restore()?;
+        Ok(())
+    }
+}
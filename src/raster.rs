@@ -0,0 +1,180 @@
+use image::RgbaImage;
+
+/// Projective transform that maps the points of from onto the points of to
+/// Returns the row major 3x3 matrix or None if three points are on a line
+pub(crate) fn homography(from: &[(f64, f64); 4], to: &[(f64, f64); 4]) -> Option<[f64; 9]> {
+    let mut system = [[0.; 9]; 8];
+    for (i, (&(x, y), &(u, v))) in from.iter().zip(to).enumerate() {
+        system[i * 2] = [x, y, 1., 0., 0., 0., -u * x, -u * y, u];
+        system[i * 2 + 1] = [0., 0., 0., x, y, 1., -v * x, -v * y, v];
+    }
+    for column in 0..8 {
+        let pivot = (column..8)
+            .max_by(|&a, &b| system[a][column].abs().total_cmp(&system[b][column].abs()))?;
+        if system[pivot][column].abs() < 1e-12 {
+            return None;
+        }
+        system.swap(column, pivot);
+        for row in 0..8 {
+            if row != column {
+                let factor = system[row][column] / system[column][column];
+                for k in column..9 {
+                    system[row][k] -= factor * system[column][k];
+                }
+            }
+        }
+    }
+    let mut matrix = [1.; 9];
+    for (i, value) in matrix.iter_mut().take(8).enumerate() {
+        *value = system[i][8] / system[i][i];
+    }
+    Some(matrix)
+}
+
+/// Applies the homography to the point
+pub(crate) fn project(matrix: &[f64; 9], (x, y): (f64, f64)) -> (f64, f64) {
+    let w = matrix[6] * x + matrix[7] * y + matrix[8];
+    (
+        (matrix[0] * x + matrix[1] * y + matrix[2]) / w,
+        (matrix[3] * x + matrix[4] * y + matrix[5]) / w,
+    )
+}
+
+/// Interpolated pixel at the position, transparent outside of the image
+pub(crate) fn bilinear(image: &RgbaImage, x: f64, y: f64) -> image::Rgba<u8> {
+    let (width, height) = image.dimensions();
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+    let at = |px: f64, py: f64| {
+        if px < 0. || py < 0. || px >= width as f64 || py >= height as f64 {
+            [0.; 4]
+        } else {
+            let p = image.get_pixel(px as u32, py as u32).0;
+            let a = p[3] as f64 / 255.;
+            [
+                p[0] as f64 * a,
+                p[1] as f64 * a,
+                p[2] as f64 * a,
+                p[3] as f64,
+            ]
+        }
+    };
+    let weights = [
+        (at(x0, y0), (1. - fx) * (1. - fy)),
+        (at(x0 + 1., y0), fx * (1. - fy)),
+        (at(x0, y0 + 1.), (1. - fx) * fy),
+        (at(x0 + 1., y0 + 1.), fx * fy),
+    ];
+    let mut sum = [0.; 4];
+    for (pixel, weight) in weights {
+        for (s, c) in sum.iter_mut().zip(pixel) {
+            *s += c * weight;
+        }
+    }
+    let alpha = sum[3];
+    if alpha <= 0. {
+        return image::Rgba([0; 4]);
+    }
+    let a = alpha / 255.;
+    image::Rgba([
+        (sum[0] / a).round().clamp(0., 255.) as u8,
+        (sum[1] / a).round().clamp(0., 255.) as u8,
+        (sum[2] / a).round().clamp(0., 255.) as u8,
+        alpha.round().clamp(0., 255.) as u8,
+    ])
+}
+
+/// Unpremultiplied copy of the pixels of an ARGB32 surface
+pub(crate) fn to_image(data: &[u8], width: u32, height: u32, stride: usize) -> RgbaImage {
+    RgbaImage::from_fn(width, height, |x, y| {
+        let i = y as usize * stride + x as usize * 4;
+        let pixel = u32::from_ne_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+        let alpha = pixel >> 24;
+        let channel = |shift: u32| match alpha {
+            0 => 0,
+            _ => ((((pixel >> shift) & 0xff) * 255 + alpha / 2) / alpha).min(255) as u8,
+        };
+        image::Rgba([channel(16), channel(8), channel(0), alpha as u8])
+    })
+}
+
+/// Writes the pixels premultiplied into an ARGB32 surface
+pub(crate) fn from_image(image: &RgbaImage, data: &mut [u8], stride: usize) {
+    for (x, y, pixel) in image.enumerate_pixels() {
+        let [r, g, b, a] = pixel.0.map(|c| c as u32);
+        let premultiply = |c: u32| (c * a + 127) / 255;
+        let value = (a << 24) | (premultiply(r) << 16) | (premultiply(g) << 8) | premultiply(b);
+        let i = y as usize * stride + x as usize * 4;
+        data[i..i + 4].copy_from_slice(&value.to_ne_bytes());
+    }
+}
+
+/// Multiplies the color channels with the alpha, like cairo keeps them
+pub(crate) fn premultiply(image: &mut RgbaImage) {
+    for pixel in image.pixels_mut() {
+        let a = pixel.0[3] as u32;
+        for c in &mut pixel.0[..3] {
+            *c = ((*c as u32 * a + 127) / 255) as u8;
+        }
+    }
+}
+
+/// Divides the color channels by the alpha again
+pub(crate) fn unpremultiply(image: &mut RgbaImage) {
+    for pixel in image.pixels_mut() {
+        let a = pixel.0[3] as u32;
+        for c in &mut pixel.0[..3] {
+            *c = match a {
+                0 => 0,
+                _ => ((*c as u32 * 255 + a / 2) / a).min(255) as u8,
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgba, RgbaImage};
+
+    use super::{bilinear, homography, project};
+
+    const SQUARE: [(f64, f64); 4] = [(0., 0.), (1., 0.), (1., 1.), (0., 1.)];
+
+    fn close(a: (f64, f64), b: (f64, f64)) -> bool {
+        (a.0 - b.0).abs() < 1e-9 && (a.1 - b.1).abs() < 1e-9
+    }
+
+    #[test]
+    fn homography_maps_the_square_onto_the_quad_and_back() {
+        let quad = [(10., 10.), (50., 5.), (60., 40.), (5., 45.)];
+        let forward = homography(&SQUARE, &quad).unwrap();
+        for (corner, target) in SQUARE.iter().zip(&quad) {
+            assert!(close(project(&forward, *corner), *target));
+        }
+        let inverse = homography(&quad, &SQUARE).unwrap();
+        let point = (0.3, 0.7);
+        assert!(close(project(&inverse, project(&forward, point)), point));
+    }
+
+    #[test]
+    fn degenerate_quad_has_no_homography() {
+        let line = [(0., 0.), (1., 1.), (2., 2.), (0., 1.)];
+        assert!(homography(&line, &SQUARE).is_none());
+        assert!(homography(&[(3., 3.); 4], &SQUARE).is_none());
+    }
+
+    #[test]
+    fn bilinear_at_centres_between_and_at_the_edge() {
+        let image = RgbaImage::from_fn(2, 1, |x, _| match x {
+            0 => Rgba([255, 0, 0, 255]),
+            _ => Rgba([0, 0, 255, 255]),
+        });
+        assert_eq!(bilinear(&image, 0., 0.), Rgba([255, 0, 0, 255]));
+        assert_eq!(bilinear(&image, 1., 0.), Rgba([0, 0, 255, 255]));
+        assert_eq!(bilinear(&image, 0.5, 0.), Rgba([128, 0, 128, 255]));
+        // half of the sample is outside of the image and transparent
+        assert_eq!(bilinear(&image, 1.5, 0.), Rgba([0, 0, 255, 128]));
+        assert_eq!(bilinear(&image, -0.5, 0.), Rgba([255, 0, 0, 128]));
+        assert_eq!(bilinear(&image, 5., 5.), Rgba([0, 0, 0, 0]));
+    }
+}
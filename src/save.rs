@@ -1,3 +1,4 @@
+use crate::augment::Augmentations;
 use crate::cairopango::Painter;
 use crate::input::OutputMode;
 use image::{DynamicImage, ImageOutputFormat, ImageResult};
@@ -18,6 +19,28 @@ pub fn export_image<W: Write + Seek>(
     image.write_to(writer, format)
 }
 
+/// Format the png of the painter is converted to, None for the formats the painter writes
+pub(crate) fn image_format(output_format: &OutputMode) -> Option<ImageOutputFormat> {
+    match output_format {
+        OutputMode::Jpeg(quality) => Some(ImageOutputFormat::Jpeg(*quality)),
+        OutputMode::Pnm(subtype) => Some(ImageOutputFormat::Pnm(*subtype)),
+        OutputMode::Ico => Some(ImageOutputFormat::Ico),
+        OutputMode::Bmp => Some(ImageOutputFormat::Bmp),
+        OutputMode::Farbfeld => Some(ImageOutputFormat::Farbfeld),
+        OutputMode::Tga => Some(ImageOutputFormat::Tga),
+        OutputMode::OpenExr => Some(ImageOutputFormat::OpenExr),
+        OutputMode::Tiff => Some(ImageOutputFormat::Tiff),
+        OutputMode::Avif => Some(ImageOutputFormat::Avif),
+        OutputMode::Qoi => Some(ImageOutputFormat::Qoi),
+        OutputMode::WebP => Some(ImageOutputFormat::WebP),
+        OutputMode::Pdf(_)
+        | OutputMode::Png(_)
+        | OutputMode::Svg
+        | OutputMode::Ps
+        | OutputMode::OpenRaster => None,
+    }
+}
+
 pub enum OutputError {
     Custom(String),
     Io(std::io::Error),
@@ -31,6 +54,25 @@ pub fn output(
     f: Painter,
     add_ext: bool
 ) -> Result<Option<Vec<u8>>, OutputError> {
+    output_augmented(output_format, filename, f, add_ext, None)
+}
+
+/// Like output, applies the augmentations to raster output before it is saved
+/// OpenRaster output with augmentations fails, they would only change the merged image
+/// and not the layers
+pub fn output_augmented(
+    output_format: OutputMode,
+    filename: PathBuf,
+    f: Painter,
+    add_ext: bool,
+    augmentations: Option<&Augmentations>
+) -> Result<Option<Vec<u8>>, OutputError> {
+    if augmentations.is_some() && output_format == OutputMode::OpenRaster {
+        return Err(OutputError::Custom(
+            "Augmentations can't be applied to the layers of OpenRaster output".to_string(),
+        ));
+    }
+
     let filepath = match add_ext {
         true => match output_format {
             OutputMode::Pdf(_) => add_ending(filename, "pdf"),
@@ -48,16 +90,22 @@ pub fn output(
             OutputMode::Avif => add_ending(filename, "avif"),
             OutputMode::Qoi => add_ending(filename, "qoi"),
             OutputMode::WebP => add_ending(filename, "webp"),
+            OutputMode::OpenRaster => add_ending(filename, "ora"),
         },
         false => Ok(filename),
     }.map_err(OutputError::Custom)?;
 
     let mut file = File::create(filepath).map_err(OutputError::Io)?;
 
+    if let Some(augmentations) = augmentations {
+        f.augment(augmentations).map_err(|e| OutputError::Custom(e.to_string()))?;
+    }
+
     if output_format == OutputMode::Svg
         || output_format == OutputMode::Png(false)
         || output_format == OutputMode::Pdf(false)
         || output_format == OutputMode::Ps
+        || output_format == OutputMode::OpenRaster
     {
         f.export(&mut file).map_err(OutputError::Custom)?;
         return Ok(None);
@@ -80,22 +128,9 @@ pub fn output(
     export_image(
         &mut file,
         image,
-        match output_format {
-            OutputMode::Jpeg(quality) => ImageOutputFormat::Jpeg(quality),
-            OutputMode::Pnm(subtype) => ImageOutputFormat::Pnm(subtype),
-            OutputMode::Ico => ImageOutputFormat::Ico,
-            OutputMode::Bmp => ImageOutputFormat::Bmp,
-            OutputMode::Farbfeld => ImageOutputFormat::Farbfeld,
-            OutputMode::Tga => ImageOutputFormat::Tga,
-            OutputMode::OpenExr => ImageOutputFormat::OpenExr,
-            OutputMode::Tiff => ImageOutputFormat::Tiff,
-            OutputMode::Avif => ImageOutputFormat::Avif,
-            OutputMode::Qoi => ImageOutputFormat::Qoi,
-            OutputMode::WebP => ImageOutputFormat::WebP,
-            OutputMode::Pdf(_) => unreachable!(),
-            OutputMode::Png(_) => unreachable!(),
-            OutputMode::Svg => unreachable!(),
-            OutputMode::Ps => unreachable!(),
+        match image_format(&output_format) {
+            Some(format) => format,
+            None => unreachable!(),
         },
     )
     .map_err(OutputError::ImageError)?;
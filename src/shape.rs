@@ -0,0 +1,94 @@
+use std::f64::consts::PI;
+
+use cairo_rs::Context;
+
+use crate::cairopango::{paint_group, PainterError};
+use crate::input::{BoxShape, BoxStyle, Shape, ShapeKind};
+use crate::path::svg_path;
+
+impl Shape {
+    /// Draws the fill and the outline as one group with the opacity and blend mode
+    /// An opaque shape with the normal blend mode is drawn directly
+    pub(crate) fn draw(&self, context: &Context) -> Result<(), PainterError> {
+        context.save()?;
+        if let Some(transform) = &self.transform {
+            transform.set(context, &self.pos, &self.size);
+        }
+        let grouped = self.blend.needs_group(self.opacity);
+        if grouped {
+            context.push_group();
+        }
+        if let Some(fill) = &self.fill {
+            context.new_path();
+            self.path(context)?;
+            fill.set(context, &self.pos)?;
+            context.fill()?;
+        }
+        if let Some(stroke) = &self.stroke {
+            context.new_path();
+            self.path(context)?;
+            stroke.set(context, &self.pos)?;
+            context.stroke()?;
+        }
+        if grouped {
+            paint_group(context, self.opacity, &self.blend)?;
+        }
+        context.restore()?;
+        Ok(())
+    }
+
+    /// Adds the outline of the shape to the path
+    fn path(&self, context: &Context) -> Result<(), cairo_rs::Error> {
+        let (x, y) = (self.pos.x, self.pos.y);
+        match &self.kind {
+            ShapeKind::Rectangle { radius } => BoxStyle {
+                radius: *radius,
+                ..Default::default()
+            }
+            .path(context, &self.pos, &self.size)?,
+            ShapeKind::Ellipse => BoxStyle {
+                shape: BoxShape::Ellipse,
+                ..Default::default()
+            }
+            .path(context, &self.pos, &self.size)?,
+            ShapeKind::Line { from, to } => {
+                context.move_to(x + from.x, y + from.y);
+                context.line_to(x + to.x, y + to.y);
+            }
+            ShapeKind::Polygon(points) => {
+                context.new_sub_path();
+                for point in points {
+                    context.line_to(x + point.x, y + point.y);
+                }
+                context.close_path();
+            }
+            ShapeKind::Path(data) => {
+                context.save()?;
+                context.translate(x, y);
+                let result = svg_path(context, data);
+                context.restore()?;
+                result?;
+            }
+            ShapeKind::Bubble { tip, spread } => {
+                let (a, b) = (self.size.width / 2., self.size.height / 2.);
+                if a <= 0. || b <= 0. {
+                    return Ok(());
+                }
+                let (cx, cy) = (x + a, y + b);
+                let (tx, ty) = (x + tip.x, y + tip.y);
+                // direction of the tip on the unit circle the ellipse is scaled from
+                let angle = ((ty - cy) / b).atan2((tx - cx) / a);
+                let half = (spread / 2.).to_radians().clamp(0., PI);
+                context.save()?;
+                context.translate(cx, cy);
+                context.scale(a, b);
+                context.new_sub_path();
+                context.arc(0., 0., 1., angle + half, angle - half + 2. * PI);
+                context.restore()?;
+                context.line_to(tx, ty);
+                context.close_path();
+            }
+        }
+        Ok(())
+    }
+}
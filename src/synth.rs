@@ -0,0 +1,434 @@
+use std::io::Cursor;
+use std::ops::Range;
+use std::path::Path;
+
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use rayon::prelude::*;
+
+use crate::annotation::Annotation;
+use crate::cairopango::PainterError;
+use crate::input::{
+    Alignments, Background, BackgroundImage, Data, Font, FontStretch, FontStyle, FontVariant,
+    FontWeight, HorizontalAlignment, ImageFilter, ImageFit, Item, Mode, OutputMode, Paint, Pos2,
+    ReadDirection, Rgba, Size2, Stroke, Styling, Text, VerticalAlignment, Wrap,
+};
+use crate::metrics::measure;
+use crate::save::image_format;
+
+/// Settings of the randomised scenes
+/// Ranges must not be empty, scene fails with SynthError::Config otherwise
+pub struct SynthConfig {
+    /// Texts the items are picked from
+    pub corpus: Vec<String>,
+    /// width and height of the scenes
+    pub size: Size2,
+    /// Backgrounds in bytes in any format the image crate decodes, they cover the scene,
+    /// empty for transparent scenes
+    pub backgrounds: Vec<Vec<u8>>,
+    /// number of items per scene
+    pub items: Range<usize>,
+    /// fontsize of an item
+    pub font_size: Range<f64>,
+    /// width of a line as part of the scene width or height
+    pub line_length: Range<f64>,
+    /// Every channel of the font color is picked between both colors
+    pub font_color: (Rgba, Rgba),
+    /// Every channel of the outline color is picked between both colors
+    pub outline_color: (Rgba, Rgba),
+    /// width of the outline, no outline when 0
+    pub outline_width: Range<f64>,
+    /// chance of vertical text from 0 to 1
+    pub vertical: f64,
+    /// tries to place an item without overlap before it is left out
+    pub attempts: usize,
+    /// Same seed and settings give the same scenes
+    pub seed: u64,
+}
+
+/// Rendered scene
+pub struct Sample {
+    pub data: Data,
+    /// The scene encoded in the output mode
+    pub image: Vec<u8>,
+    pub annotations: Vec<Annotation>,
+}
+
+pub enum SynthError {
+    Io(std::io::Error),
+    Font(String),
+    Painter(PainterError),
+    Export(String),
+    /// A setting of SynthConfig can't be used, like an empty range
+    Config(String),
+}
+
+/// Font families the items are picked from
+/// The fonts have to be installed or registered so pango finds them, the crate doesn't load fonts
+pub struct Fonts {
+    families: Vec<String>,
+}
+
+impl Fonts {
+    /// Families installed on the system, the default font of pango when empty
+    pub fn system(families: Vec<String>) -> Fonts {
+        Fonts { families }
+    }
+
+    /// Families of the font files in dir
+    /// dir has to be installed or registered, for example as a dir in fonts.conf
+    pub fn from_dir(dir: &Path) -> Result<Fonts, SynthError> {
+        Ok(Fonts {
+            families: font_families(dir)?,
+        })
+    }
+
+    /// Family names of the fonts the items are picked from
+    pub fn families(&self) -> &[String] {
+        &self.families
+    }
+}
+
+/// Family names of the ttf, otf and collection files in dir
+fn font_families(dir: &Path) -> Result<Vec<String>, SynthError> {
+    let mut families = vec![];
+    for entry in std::fs::read_dir(dir).map_err(SynthError::Io)? {
+        let path = entry.map_err(SynthError::Io)?.path();
+        let extension = path.extension().map(|e| e.to_string_lossy().to_lowercase());
+        if !matches!(extension.as_deref(), Some("ttf" | "otf" | "ttc" | "otc")) {
+            continue;
+        }
+        let data = std::fs::read(&path).map_err(SynthError::Io)?;
+        for index in 0..ttf_parser::fonts_in_collection(&data).unwrap_or(1) {
+            let face = ttf_parser::Face::parse(&data, index)
+                .map_err(|e| SynthError::Font(format!("{}: {}", path.display(), e)))?;
+            let family = face
+                .names()
+                .into_iter()
+                .filter(|name| name.name_id == ttf_parser::name_id::FAMILY && name.is_unicode())
+                .find_map(|name| name.to_string());
+            if let Some(family) = family {
+                if !families.contains(&family) {
+                    families.push(family);
+                }
+            }
+        }
+    }
+    Ok(families)
+}
+
+/// Renders count scenes in parallel
+/// Every scene has its own random generator so the scenes don't depend on the number of threads
+pub fn generate(
+    config: &SynthConfig,
+    fonts: &Fonts,
+    count: usize,
+    output_mode: &OutputMode,
+) -> Vec<Result<Sample, SynthError>> {
+    (0..count)
+        .into_par_iter()
+        .map(|index| render(config, fonts, index as u64, output_mode))
+        .collect()
+}
+
+/// Renders the scene with the index
+pub fn render(
+    config: &SynthConfig,
+    fonts: &Fonts,
+    index: u64,
+    output_mode: &OutputMode,
+) -> Result<Sample, SynthError> {
+    let data = scene(config, fonts, index)?;
+    let painter = data
+        .painter(output_mode, Some(&config.size))
+        .map_err(SynthError::Painter)?;
+    let mut image = vec![];
+    painter.export(&mut image).map_err(SynthError::Export)?;
+    // raster formats other than png are converted from the png of the painter
+    if let Some(format) = image_format(output_mode) {
+        let decoded =
+            image::load_from_memory(&image).map_err(|e| SynthError::Export(e.to_string()))?;
+        let mut encoded = Cursor::new(vec![]);
+        decoded
+            .write_to(&mut encoded, format)
+            .map_err(|e| SynthError::Export(e.to_string()))?;
+        image = encoded.into_inner();
+    }
+    if let OutputMode::Png(true) = output_mode {
+        image = oxipng::optimize_from_memory(&image, &oxipng::Options::default())
+            .map_err(|e| SynthError::Export(e.to_string()))?;
+    }
+    let annotations = painter.annotations().to_vec();
+    Ok(Sample {
+        data,
+        image,
+        annotations,
+    })
+}
+
+/// Randomised scene with the index
+/// The texts are measured, so the fonts have to be loaded
+pub fn scene(config: &SynthConfig, fonts: &Fonts, index: u64) -> Result<Data, SynthError> {
+    config.validate()?;
+    let mut rng = ChaCha8Rng::seed_from_u64(config.seed);
+    rng.set_stream(index);
+
+    let global_align = Alignments {
+        ha: HorizontalAlignment::Left,
+        va: VerticalAlignment::Top,
+    };
+    let mut placed: Vec<(Pos2, Size2)> = vec![];
+    let mut items = vec![];
+    for _ in 0..rng.gen_range(config.items.clone()) {
+        let value = match config.corpus.choose(&mut rng) {
+            Some(value) => value.clone(),
+            None => break,
+        };
+        let vertical = rng.gen_bool(config.vertical.clamp(0., 1.));
+        let style = random_style(&mut rng, fonts.families(), vertical);
+        let font_size = rng.gen_range(config.font_size.clone());
+        let outline = rng.gen_range(config.outline_width.clone());
+        let strokes = match outline > 0. {
+            true => vec![Stroke::new(
+                outline,
+                Paint::Solid(random_color(&mut rng, &config.outline_color)),
+            )],
+            false => vec![],
+        };
+        let length = rng.gen_range(config.line_length.clone())
+            * match vertical {
+                true => config.size.height,
+                false => config.size.width,
+            };
+        let mut text = Text {
+            mode: Mode::Text,
+            value,
+            pos: Pos2::new(0., 0.),
+            size: match vertical {
+                true => Size2::new(config.size.width, length),
+                false => Size2::new(length, config.size.height),
+            },
+            font_size,
+            font_color: Paint::Solid(random_color(&mut rng, &config.font_color)),
+            background: Background::None,
+            style: None,
+            align: None,
+            strokes,
+            effects: vec![],
+            paint_order: Default::default(),
+            transform: None,
+            path: None,
+            region: Default::default(),
+            frames: vec![],
+            overflow: Default::default(),
+            decoration: Default::default(),
+            quad: None,
+            layer: 0,
+            z_index: 0,
+            opacity: 1.,
+            blend: Default::default(),
+        };
+
+        let metrics =
+            measure(&text, &style, &global_align).map_err(|e| SynthError::Painter(e.into()))?;
+        let margin = outline.ceil();
+        text.size = match vertical {
+            true => Size2::new(metrics.logical.width.ceil() + margin, length),
+            false => Size2::new(length, metrics.logical.height.ceil() + margin),
+        };
+        text.style = Some(style);
+        let free_x = config.size.width - text.size.width;
+        let free_y = config.size.height - text.size.height;
+        if free_x < 0. || free_y < 0. {
+            continue;
+        }
+        for _ in 0..config.attempts {
+            let pos = Pos2::new(rng.gen_range(0.0..=free_x), rng.gen_range(0.0..=free_y));
+            if placed
+                .iter()
+                .all(|other| !overlaps(&pos, &text.size, other))
+            {
+                placed.push((
+                    Pos2::new(pos.x, pos.y),
+                    Size2::new(text.size.width, text.size.height),
+                ));
+                text.pos = pos;
+                items.push(Item::Text(text));
+                break;
+            }
+        }
+    }
+
+    Ok(Data {
+        items,
+        global_style: random_style(&mut rng, &[], false),
+        global_align,
+        background: config
+            .backgrounds
+            .choose(&mut rng)
+            .map(|bytes| BackgroundImage {
+                bytes: bytes.clone(),
+                fit: ImageFit::Cover,
+                filter: ImageFilter::default(),
+            }),
+        layers: vec![],
+    })
+}
+
+impl SynthConfig {
+    /// Checks the ranges the scenes are picked from
+    fn validate(&self) -> Result<(), SynthError> {
+        if self.items.is_empty() {
+            return Err(SynthError::Config("items is an empty range".to_string()));
+        }
+        for (name, range) in [
+            ("font_size", &self.font_size),
+            ("line_length", &self.line_length),
+            ("outline_width", &self.outline_width),
+        ] {
+            if range.is_empty() || !range.start.is_finite() || !range.end.is_finite() {
+                return Err(SynthError::Config(format!(
+                    "{} is an empty or infinite range",
+                    name
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn overlaps(pos: &Pos2, size: &Size2, other: &(Pos2, Size2)) -> bool {
+    let (other_pos, other_size) = other;
+    pos.x < other_pos.x + other_size.width
+        && other_pos.x < pos.x + size.width
+        && pos.y < other_pos.y + other_size.height
+        && other_pos.y < pos.y + size.height
+}
+
+fn random_color(rng: &mut ChaCha8Rng, range: &(Rgba, Rgba)) -> Rgba {
+    let (a, b) = range;
+    let mut channel = |a: f64, b: f64| a + (b - a) * rng.gen::<f64>();
+    Rgba::new(
+        channel(a.r, b.r),
+        channel(a.g, b.g),
+        channel(a.b, b.b),
+        channel(a.a, b.a),
+    )
+}
+
+fn random_style(rng: &mut ChaCha8Rng, fonts: &[String], vertical: bool) -> Styling {
+    let font = fonts.choose(rng).map(|family| Font {
+        font_family: family.clone(),
+        variant: FontVariant::Normal,
+        stretch: match rng.gen_range(0..3) {
+            0 => FontStretch::Condensed,
+            1 => FontStretch::Normal,
+            _ => FontStretch::Expanded,
+        },
+        weight: match rng.gen_range(0..4) {
+            0 => FontWeight::Light,
+            1 => FontWeight::Normal,
+            2 => FontWeight::Semibold,
+            _ => FontWeight::Bold,
+        },
+        style: match rng.gen_range(0..3) {
+            0 => FontStyle::Normal,
+            1 => FontStyle::Italic,
+            _ => FontStyle::Oblique,
+        },
+    });
+    Styling {
+        spacing: None,
+        line_spacing: None,
+        ellipsize: Default::default(),
+        wrap: match rng.gen_range(0..3) {
+            0 => Wrap::Word,
+            1 => Wrap::Char,
+            _ => Wrap::WordChar,
+        },
+        indent: None,
+        single_paragraph_mode: false,
+        auto_dir: true,
+        read_direction: ReadDirection::default(),
+        vertical,
+        font,
+        justiy: false,
+        justify_last_line: false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{scene, Fonts, SynthConfig, SynthError};
+    use crate::input::{Data, Item, Rgba, Size2};
+
+    fn config() -> SynthConfig {
+        SynthConfig {
+            corpus: vec!["Hello".to_string(), "synthetic text".to_string()],
+            size: Size2::new(400., 300.),
+            backgrounds: vec![],
+            items: 1..5,
+            font_size: 10.0..30.0,
+            line_length: 0.2..0.8,
+            font_color: (Rgba::new(0., 0., 0., 1.), Rgba::new(1., 1., 1., 1.)),
+            outline_color: (Rgba::new(0., 0., 0., 1.), Rgba::new(1., 1., 1., 1.)),
+            outline_width: 0.0..3.0,
+            vertical: 0.5,
+            attempts: 10,
+            seed: 7,
+        }
+    }
+
+    /// Text, position, size, fontsize and direction of every item
+    fn summary(data: &Data) -> Vec<(String, [f64; 5], bool)> {
+        data.items
+            .iter()
+            .map(|item| match item {
+                Item::Text(text) => (
+                    text.value.clone(),
+                    [
+                        text.pos.x,
+                        text.pos.y,
+                        text.size.width,
+                        text.size.height,
+                        text.font_size,
+                    ],
+                    text.style.as_ref().is_some_and(|style| style.vertical),
+                ),
+                _ => unreachable!("scenes only contain text"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn same_seed_and_index_give_the_same_scene() {
+        let fonts = Fonts::system(vec![]);
+        let config = config();
+        let first = scene(&config, &fonts, 3).ok().unwrap();
+        let second = scene(&config, &fonts, 3).ok().unwrap();
+        assert!(!first.items.is_empty());
+        assert_eq!(summary(&first), summary(&second));
+    }
+
+    #[test]
+    fn empty_ranges_are_rejected() {
+        let fonts = Fonts::system(vec![]);
+        let config = SynthConfig {
+            items: 2..2,
+            ..config()
+        };
+        assert!(matches!(
+            scene(&config, &fonts, 0),
+            Err(SynthError::Config(_))
+        ));
+        let config = SynthConfig {
+            font_size: 12.0..12.0,
+            ..config()
+        };
+        assert!(matches!(
+            scene(&config, &fonts, 0),
+            Err(SynthError::Config(_))
+        ));
+    }
+}